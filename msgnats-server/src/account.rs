@@ -0,0 +1,286 @@
+use crate::auth::User;
+use crate::client::ClientMessageSender;
+use crate::simple_sublist::{ArcSubResult, SubListTrait};
+use crate::trie_sublist::{subject_matches, FWC, PWC};
+use rand::{distributions::Alphanumeric, Rng};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+/**
+账户之间的主题相互隔离,每个账户有自己的订阅列表,
+一个账户中的客户端发布的消息不会投递给其他账户的订阅者.
+```text
+accounts {
+    A {
+        users = [{user: a, password: a}]
+        max_subscriptions: 1000
+    }
+    B {
+        users = [{user: b, password: b}]
+    }
+}
+```
+authorization中配置的用户以及不需要认证时的客户端都属于全局账户.
+
+账户可以导出stream或者service给其他账户导入:
+```text
+A {
+    exports = [
+        {stream: "events.>"}
+        {service: "api.billing.*", accounts: [B]}
+    ]
+}
+B {
+    imports = [
+        {stream: {account: A, subject: "events.>"}, prefix: a}
+        {service: {account: A, subject: "api.billing.*"}, to: "billing.*"}
+    ]
+}
+```
+- stream: A中发布到events.x的消息,B中的订阅者会在a.events.x上收到
+- service: B中发布到billing.get的请求,A中的订阅者会在api.billing.get上收到,
+  请求的reply_to被替换成A中一次性的_R_.<id>,A回复到这个主题的响应再投递回B原来的reply_to
+*/
+pub const GLOBAL_ACCOUNT: &str = "$G";
+// 服务请求替换后的reply_to前缀
+pub const RESPONSE_PREFIX: &str = "_R_.";
+// 服务请求一直没有响应时,超过这个时间清理响应映射
+pub const RESPONSE_TTL: Duration = Duration::from_secs(120);
+// 清理响应映射的最小间隔,避免每次发布都遍历所有响应映射
+const RESPONSE_PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+pub const RESPONSE_ID_LEN: usize = 16;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct AccountConfig {
+    pub users: Vec<User>,
+    pub exports: Vec<Export>,
+    pub imports: Vec<Import>,
+    pub max_subscriptions: usize, // 账户中所有客户端的订阅总数,0表示不限制
+}
+
+/**
+ * stream和service只能配置一个,accounts为空时允许所有账户导入
+ */
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct Export {
+    pub stream: Option<String>,
+    pub service: Option<String>,
+    pub accounts: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct Import {
+    pub stream: Option<ImportSource>,
+    pub service: Option<ImportSource>,
+    pub prefix: Option<String>, // 在导入的主题前加上前缀
+    pub to: Option<String>,     // 映射成本账户中的主题,通配符按顺序对应
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ImportSource {
+    pub account: String,
+    pub subject: String,
+}
+
+impl AccountConfig {
+    /**
+     * 这个账户是否把包含subject的stream或者service导出给了importer
+     */
+    pub fn exports(&self, subject: &str, importer: &str, service: bool) -> bool {
+        self.exports.iter().any(|export| {
+            let pattern = if service {
+                &export.service
+            } else {
+                &export.stream
+            };
+            matches!(pattern, Some(pattern) if subject_matches(pattern, subject))
+                && (export.accounts.is_empty() || export.accounts.iter().any(|a| a == importer))
+        })
+    }
+}
+
+impl Import {
+    // 导入的主题在本账户中对应的主题
+    pub fn local_subject(&self, source: &ImportSource) -> String {
+        match (&self.to, &self.prefix) {
+            (Some(to), _) => to.clone(),
+            (None, Some(prefix)) => format!("{}.{}", prefix, source.subject),
+            (None, None) => source.subject.clone(),
+        }
+    }
+}
+
+/**
+ * 按照通配符的位置把subject从from映射到to,比如
+ * map_subject("billing.*", "api.billing.*", "billing.get") == "api.billing.get"
+ * subject必须能被from匹配,from和to中的通配符按顺序对应
+ */
+pub fn map_subject(from: &str, to: &str, subject: &str) -> String {
+    let tokens: Vec<&str> = subject.split('.').collect();
+    let mut wildcards = Vec::new();
+    for (i, token) in from.split('.').enumerate() {
+        match token {
+            PWC => wildcards.extend(tokens.get(i).map(|t| t.to_string())),
+            FWC => wildcards.extend(tokens.get(i..).map(|t| t.join("."))),
+            _ => {}
+        }
+    }
+    let mut wildcards = wildcards.into_iter();
+    to.split('.')
+        .map(|token| match token {
+            PWC | FWC => wildcards.next().unwrap_or_else(|| token.to_string()),
+            _ => token.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+// 服务请求的响应需要投递到的账户和主题
+#[derive(Debug, Clone)]
+pub struct ResponseRoute {
+    pub account: String,
+    pub reply_to: String,
+    pub created: Instant,
+}
+
+/**
+ * 一条发布的消息在某个账户中对应的主题以及匹配到的订阅
+ */
+#[derive(Debug)]
+pub struct Route {
+    pub account: String,
+    pub subject: String,
+    pub reply_to: Option<String>,
+    pub result: ArcSubResult,
+    pub gateways: Vec<Arc<Mutex<ClientMessageSender>>>, // 没有订阅也需要发送的乐观模式网关
+}
+
+#[derive(Debug, Default)]
+pub struct Account<T: SubListTrait> {
+    pub name: String,
+    pub sub_list: T,                               // 账户自己的订阅列表
+    pub clients: HashSet<u64>,                     // 属于这个账户的客户端
+    pub responses: HashMap<String, ResponseRoute>, // 导出的服务还没有响应的请求
+    pub interest: HashMap<Interest, usize>, // 本地客户端以及叶子节点订阅的主题和订阅数量,传播给路由
+    pub leaf_interest: HashMap<Interest, usize>, // 所有订阅的主题和订阅数量,传播给叶子节点和网关
+    pub client_subs: usize, // 本服务端客户端的订阅数量,账户的max_subscriptions只限制这部分
+    pub queue_subs: HashMap<Interest, usize>, // 各个queue group的成员数量,包括其他服务端的订阅
+    responses_pruned: Option<Instant>, // 上一次清理响应映射的时间
+}
+
+// 向其他服务端传播的订阅兴趣,(subject, queue)
+pub type Interest = (String, Option<String>);
+
+impl<T: SubListTrait> Account<T> {
+    pub fn new(name: &str, sub_list: T) -> Self {
+        Self {
+            name: name.to_string(),
+            sub_list,
+            clients: HashSet::new(),
+            responses: HashMap::new(),
+            interest: HashMap::new(),
+            leaf_interest: HashMap::new(),
+            client_subs: 0,
+            queue_subs: HashMap::new(),
+            responses_pruned: None,
+        }
+    }
+
+    /**
+     * 清理超时没有响应的请求,发布消息以及记录新请求时调用
+     */
+    pub fn prune_responses(&mut self) {
+        let pruned = self.responses_pruned;
+        if self.responses.is_empty()
+            || pruned.is_some_and(|t| t.elapsed() < RESPONSE_PRUNE_INTERVAL)
+        {
+            return;
+        }
+        self.responses_pruned = Some(Instant::now());
+        self.responses
+            .retain(|_, response| response.created.elapsed() < RESPONSE_TTL);
+    }
+
+    /**
+     * 记录一个服务请求,返回替换后的reply_to
+     * 顺便清理超时没有响应的请求
+     */
+    pub fn add_response(&mut self, account: &str, reply_to: &str) -> String {
+        self.prune_responses();
+        let id: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(RESPONSE_ID_LEN)
+            .map(char::from)
+            .collect();
+        let subject = format!("{}{}", RESPONSE_PREFIX, id);
+        self.responses.insert(
+            subject.clone(),
+            ResponseRoute {
+                account: account.to_string(),
+                reply_to: reply_to.to_string(),
+                created: Instant::now(),
+            },
+        );
+        subject
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trie_sublist::TrieSubList;
+
+    #[test]
+    fn test_map_subject() {
+        assert_eq!(
+            map_subject("billing.*", "api.billing.*", "billing.get"),
+            "api.billing.get"
+        );
+        assert_eq!(
+            map_subject("events.>", "a.events.>", "events.x.y"),
+            "a.events.x.y"
+        );
+        assert_eq!(map_subject("a.*.*", "b.*.x.*", "a.1.2"), "b.1.x.2");
+        assert_eq!(map_subject("foo", "bar", "foo"), "bar");
+    }
+
+    #[test]
+    fn test_exports() {
+        let account: AccountConfig = serde_json::from_str(
+            r#"{"exports":[{"stream":"events.>"},{"service":"api.*","accounts":["B"]}]}"#,
+        )
+        .unwrap();
+        assert!(account.exports("events.>", "C", false));
+        assert!(account.exports("events.x", "C", false));
+        assert!(!account.exports("events.x", "C", true));
+        assert!(account.exports("api.get", "B", true));
+        assert!(!account.exports("api.get", "C", true));
+        assert!(!account.exports("api.>", "B", true));
+    }
+
+    #[test]
+    fn test_prune_responses() {
+        let mut account = Account::new("A", TrieSubList::default());
+        let reply_to = account.add_response("B", "_INBOX.1");
+        account.responses.get_mut(&reply_to).unwrap().created =
+            Instant::now().checked_sub(RESPONSE_TTL).unwrap();
+        account.add_response("B", "_INBOX.2");
+        assert_eq!(account.responses.len(), 1);
+        // 距离上次清理太近时不会清理
+        let reply_to = account.add_response("B", "_INBOX.3");
+        account.responses.get_mut(&reply_to).unwrap().created =
+            Instant::now().checked_sub(RESPONSE_TTL).unwrap();
+        account.prune_responses();
+        assert_eq!(account.responses.len(), 2);
+        account.responses_pruned = None;
+        account.prune_responses();
+        assert_eq!(account.responses.len(), 1);
+    }
+}
@@ -0,0 +1,307 @@
+use crate::config::duration_format;
+use crate::info::ConnectOptions;
+use crate::trie_sublist::subject_matches;
+use serde_derive::{Deserialize, Serialize};
+use std::time::Duration;
+
+/**
+客户端认证配置,和NATS的authorization块一致:
+```text
+authorization {
+    user: admin
+    password: "$2a$11$..."  # 明文或者bcrypt哈希
+    token: s3cr3t
+    timeout: 2
+    users = [
+        {user: alice, password: foo, permissions: {publish: "orders.>", subscribe: {deny: "admin.>"}}}
+    ]
+    default_permissions {
+        publish: ["public.>"]
+    }
+}
+```
+没有配置任何用户和token时不需要认证.
+没有配置permissions的用户使用default_permissions,都没有配置时允许所有主题
+*/
+pub const DEFAULT_AUTH_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct AuthConfig {
+    pub user: Option<String>,
+    #[serde(alias = "pass")]
+    pub password: Option<String>,
+    pub token: Option<String>,
+    #[serde(with = "duration_format")]
+    pub timeout: Duration, // 连接建立后在这段时间内没有通过认证就断开
+    pub users: Vec<User>,
+    pub default_permissions: Permissions,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct User {
+    pub user: String,
+    #[serde(alias = "pass")]
+    pub password: String,
+    pub permissions: Option<Permissions>,
+}
+
+/**
+ * 用户可以发布和订阅的主题
+ */
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct Permissions {
+    pub publish: SubjectPermission,
+    pub subscribe: SubjectPermission,
+}
+
+/**
+ * allow为空表示允许所有主题,deny优先于allow
+ * 配置中可以只写一个主题或者主题列表,表示allow
+ */
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(from = "PermissionValue")]
+pub struct SubjectPermission {
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PermissionValue {
+    Subject(String),
+    Subjects(Vec<String>),
+    Full {
+        #[serde(default)]
+        allow: Subjects,
+        #[serde(default)]
+        deny: Subjects,
+    },
+}
+
+#[derive(Default, Deserialize)]
+#[serde(untagged)]
+enum Subjects {
+    #[default]
+    None,
+    One(String),
+    Many(Vec<String>),
+}
+
+impl From<Subjects> for Vec<String> {
+    fn from(subjects: Subjects) -> Self {
+        match subjects {
+            Subjects::None => Vec::new(),
+            Subjects::One(subject) => vec![subject],
+            Subjects::Many(subjects) => subjects,
+        }
+    }
+}
+
+impl From<PermissionValue> for SubjectPermission {
+    fn from(value: PermissionValue) -> Self {
+        match value {
+            PermissionValue::Subject(subject) => Self {
+                allow: vec![subject],
+                deny: Vec::new(),
+            },
+            PermissionValue::Subjects(allow) => Self {
+                allow,
+                deny: Vec::new(),
+            },
+            PermissionValue::Full { allow, deny } => Self {
+                allow: allow.into(),
+                deny: deny.into(),
+            },
+        }
+    }
+}
+
+impl SubjectPermission {
+    /**
+     * subject可以包含通配符,这时要求subject能匹配的主题都在allow中
+     */
+    pub fn permits(&self, subject: &str) -> bool {
+        (self.allow.is_empty() || self.allow.iter().any(|p| subject_matches(p, subject)))
+            && !self.denies(subject)
+    }
+
+    // 订阅主题包含通配符时只有部分主题被拒绝,投递时需要再检查一次
+    pub fn denies(&self, subject: &str) -> bool {
+        self.deny.iter().any(|p| subject_matches(p, subject))
+    }
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            user: None,
+            password: None,
+            token: None,
+            timeout: DEFAULT_AUTH_TIMEOUT,
+            users: Vec::new(),
+            default_permissions: Permissions::default(),
+        }
+    }
+}
+
+impl AuthConfig {
+    pub fn is_required(&self) -> bool {
+        self.user.is_some() || self.token.is_some() || !self.users.is_empty()
+    }
+
+    /**
+     * 检查CONNECT中的auth_token或者user/pass,通过时返回这个连接的权限
+     */
+    pub fn authenticate(&self, opts: &ConnectOptions) -> Option<&Permissions> {
+        if !self.is_required() {
+            return Some(&self.default_permissions);
+        }
+        if let (Some(token), Some(auth_token)) = (&self.token, &opts.auth_token) {
+            if constant_time_eq(token.as_bytes(), auth_token.as_bytes()) {
+                return Some(&self.default_permissions);
+            }
+        }
+        let (user, pass) = match (&opts.user, &opts.pass) {
+            (Some(user), Some(pass)) => (user, pass),
+            _ => return None,
+        };
+        if let Some(ref expected) = self.user {
+            if expected == user
+                && check_password(self.password.as_deref().unwrap_or_default(), pass)
+            {
+                return Some(&self.default_permissions);
+            }
+        }
+        find_user(&self.users, opts)
+            .map(|u| u.permissions.as_ref().unwrap_or(&self.default_permissions))
+    }
+}
+
+/**
+ * 在用户列表中查找CONNECT中user/pass都匹配的用户
+ */
+pub fn find_user<'a>(users: &'a [User], opts: &ConnectOptions) -> Option<&'a User> {
+    let (user, pass) = match (&opts.user, &opts.pass) {
+        (Some(user), Some(pass)) => (user, pass),
+        _ => return None,
+    };
+    users
+        .iter()
+        .find(|u| &u.user == user && check_password(&u.password, pass))
+}
+
+/**
+ * 在用户列表中查找用户名是TLS客户端证书中某个身份的用户
+ */
+pub fn find_user_by_identity<'a>(users: &'a [User], identities: &[String]) -> Option<&'a User> {
+    identities
+        .iter()
+        .find_map(|identity| users.iter().find(|u| &u.user == identity))
+}
+
+// bcrypt哈希以$2a$、$2b$、$2y$开头,否则按照明文比较
+fn check_password(expected: &str, pass: &str) -> bool {
+    if ["$2a$", "$2b$", "$2y$"]
+        .iter()
+        .any(|p| expected.starts_with(p))
+    {
+        bcrypt::verify(pass, expected).unwrap_or(false)
+    } else {
+        constant_time_eq(expected.as_bytes(), pass.as_bytes())
+    }
+}
+
+// 比较时间和内容无关,避免通过响应时间猜测密码
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0, |r, (x, y)| r | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connect(user: Option<&str>, pass: Option<&str>, token: Option<&str>) -> ConnectOptions {
+        ConnectOptions {
+            user: user.map(|s| s.to_string()),
+            pass: pass.map(|s| s.to_string()),
+            auth_token: token.map(|s| s.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_no_auth() {
+        let auth = AuthConfig::default();
+        assert!(!auth.is_required());
+        assert!(auth.authenticate(&connect(None, None, None)).is_some());
+    }
+
+    #[test]
+    fn test_user_and_token() {
+        let auth = AuthConfig {
+            user: Some("admin".to_string()),
+            password: Some("secret".to_string()),
+            token: Some("t0ken".to_string()),
+            users: vec![User {
+                user: "alice".to_string(),
+                password: bcrypt::hash("foo", 4).unwrap(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        assert!(auth.is_required());
+        assert!(auth
+            .authenticate(&connect(Some("admin"), Some("secret"), None))
+            .is_some());
+        assert!(auth
+            .authenticate(&connect(None, None, Some("t0ken")))
+            .is_some());
+        assert!(auth
+            .authenticate(&connect(Some("alice"), Some("foo"), None))
+            .is_some());
+        assert!(auth
+            .authenticate(&connect(Some("alice"), Some("secret"), None))
+            .is_none());
+        assert!(auth
+            .authenticate(&connect(Some("admin"), Some("secre"), None))
+            .is_none());
+        assert!(auth
+            .authenticate(&connect(Some("admin"), None, Some("x")))
+            .is_none());
+        assert!(auth.authenticate(&connect(None, None, None)).is_none());
+    }
+
+    #[test]
+    fn test_permissions() {
+        let auth: AuthConfig = serde_json::from_str(
+            r#"{"users":[
+                {"user":"a","password":"a","permissions":{"publish":"orders.>","subscribe":{"allow":["orders.*","admin.>"],"deny":"admin.secret"}}},
+                {"user":"b","password":"b"}
+            ],"default_permissions":{"publish":["public.>","status"]}}"#,
+        )
+        .unwrap();
+        let perms = auth
+            .authenticate(&connect(Some("a"), Some("a"), None))
+            .unwrap();
+        assert!(perms.publish.permits("orders.new"));
+        assert!(!perms.publish.permits("orders"));
+        assert!(!perms.publish.permits("public.x"));
+        assert!(perms.subscribe.permits("orders.*"));
+        assert!(!perms.subscribe.permits("orders.>"));
+        assert!(!perms.subscribe.permits("admin.secret"));
+        // 通配符订阅只有部分主题被拒绝,订阅允许,投递时过滤
+        assert!(perms.subscribe.permits("admin.*"));
+        assert!(perms.subscribe.denies("admin.secret"));
+
+        let perms = auth
+            .authenticate(&connect(Some("b"), Some("b"), None))
+            .unwrap();
+        assert!(perms.publish.permits("status"));
+        assert!(perms.publish.permits("public.a.b"));
+        assert!(!perms.publish.permits("orders.new"));
+        assert!(perms.subscribe.permits(">"));
+    }
+}
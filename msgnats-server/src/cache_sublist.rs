@@ -0,0 +1,151 @@
+use crate::errors::Result;
+use crate::simple_sublist::{ArcSubResult, ArcSubscription, SubListTrait};
+use crate::trie_sublist::subject_matches;
+use lru::LruCache;
+use std::cell::{Cell, RefCell};
+use std::num::NonZeroUsize;
+
+/**
+在真正的SubList前面加一层LRU缓存,缓存最近pub过的subject的查找结果.
+insert/remove时,把缓存中所有能被这个订阅主题匹配到的subject删掉,
+这样带通配符的订阅变化也能正确失效.
+match_subject只拿到&self,而SubList总是在server的锁里面访问,
+所以这里用RefCell/Cell做内部可变,不需要额外加锁.
+*/
+// 默认缓存的subject数量
+pub const DEFAULT_CACHE_SIZE: usize = 1024;
+
+#[derive(Debug)]
+pub struct CacheSubList<T: SubListTrait> {
+    inner: T,
+    cache: RefCell<LruCache<String, ArcSubResult>>,
+    hits: Cell<u64>,
+    misses: Cell<u64>,
+}
+
+impl<T: SubListTrait> CacheSubList<T> {
+    // capacity为0时按照1处理
+    pub fn new(inner: T, capacity: usize) -> Self {
+        let capacity = NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN);
+        Self {
+            inner,
+            cache: RefCell::new(LruCache::new(capacity)),
+            hits: Cell::new(0),
+            misses: Cell::new(0),
+        }
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.get()
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.get()
+    }
+
+    // 当前缓存的subject数量
+    pub fn cached(&self) -> usize {
+        self.cache.borrow().len()
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    // 删除缓存中所有被pattern匹配到的subject
+    fn invalidate(&mut self, pattern: &str) {
+        let cache = self.cache.get_mut();
+        let stale: Vec<String> = cache
+            .iter()
+            .filter(|(subject, _)| subject_matches(pattern, subject))
+            .map(|(subject, _)| subject.clone())
+            .collect();
+        for subject in stale {
+            cache.pop(&subject);
+        }
+    }
+}
+
+impl<T: SubListTrait + Default> Default for CacheSubList<T> {
+    fn default() -> Self {
+        Self::new(T::default(), DEFAULT_CACHE_SIZE)
+    }
+}
+
+impl<T: SubListTrait> SubListTrait for CacheSubList<T> {
+    fn insert(&mut self, sub: ArcSubscription) -> Result<()> {
+        self.inner.insert(sub.clone())?;
+        self.invalidate(&sub.subject);
+        Ok(())
+    }
+
+    fn remove(&mut self, sub: ArcSubscription) -> Result<()> {
+        self.inner.remove(sub.clone())?;
+        self.invalidate(&sub.subject);
+        Ok(())
+    }
+
+    fn match_subject(&self, subject: &str) -> Result<ArcSubResult> {
+        if let Some(r) = self.cache.borrow_mut().get(subject) {
+            self.hits.set(self.hits.get() + 1);
+            return Ok(r.clone());
+        }
+        self.misses.set(self.misses.get() + 1);
+        let r = self.inner.match_subject(subject)?;
+        self.cache.borrow_mut().put(subject.to_string(), r.clone());
+        Ok(r)
+    }
+
+    fn count(&self) -> usize {
+        self.inner.count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ClientMessageSender;
+    use crate::simple_sublist::SubScription;
+    use crate::trie_sublist::TrieSubList;
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+
+    fn new_sub(subject: &str, sid: &str) -> ArcSubscription {
+        let sender = Arc::new(Mutex::new(ClientMessageSender::new(tokio::io::sink())));
+        Arc::new(SubScription::new(sender, subject, None, sid))
+    }
+
+    #[test]
+    fn test_hit_and_invalidate() {
+        let mut sl = CacheSubList::new(TrieSubList::default(), 16);
+        let s1 = new_sub("a.b", "1");
+        sl.insert(s1.clone()).unwrap();
+        assert_eq!(sl.match_subject("a.b").unwrap().ppubs.len(), 1);
+        assert_eq!(sl.match_subject("a.b").unwrap().ppubs.len(), 1);
+        assert_eq!((sl.hits(), sl.misses()), (1, 1));
+
+        sl.match_subject("a.c").unwrap();
+        sl.match_subject("x.y").unwrap();
+        assert_eq!(sl.cached(), 3);
+        // 通配符订阅只让能匹配到的缓存失效
+        sl.insert(new_sub("a.*", "2")).unwrap();
+        assert_eq!(sl.cached(), 1);
+        assert_eq!(sl.match_subject("a.b").unwrap().ppubs.len(), 2);
+        assert_eq!(sl.match_subject("a.c").unwrap().ppubs.len(), 1);
+
+        sl.remove(s1).unwrap();
+        assert_eq!(sl.match_subject("a.b").unwrap().ppubs.len(), 1);
+        assert_eq!(sl.hits(), 1);
+    }
+
+    #[test]
+    fn test_capacity() {
+        let sl = CacheSubList::new(TrieSubList::default(), 2);
+        for subject in ["a", "b", "c"] {
+            sl.match_subject(subject).unwrap();
+        }
+        assert_eq!(sl.cached(), 2);
+        sl.match_subject("a").unwrap();
+        assert_eq!((sl.hits(), sl.misses()), (0, 4));
+    }
+}
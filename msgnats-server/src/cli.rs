@@ -0,0 +1,178 @@
+use crate::conf_parser::parse_size;
+use crate::config::ServerConfig;
+use std::path::PathBuf;
+
+/**
+ * 命令行参数,指定的参数覆盖配置文件中的值
+ */
+pub const USAGE: &str = "\
+Usage: msgnats-server [options]
+
+Options:
+    -a, --addr <host>          Bind to host address (default: 127.0.0.1)
+    -p, --port <port>          Use port for clients (default: 18888)
+    -c, --config <file>        Configuration file, NATS conf format or JSON
+    -l, --log-level <level>    Log level: off, error, warn, info, debug, trace (default: info)
+        --max-payload <size>   Maximum message payload, e.g. 1048576 or 1MB
+        --pid-file <file>      File to store the process id
+    -h, --help                 Show this message
+    -v, --version              Show version
+";
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Options {
+    pub addr: Option<String>,
+    pub port: Option<u16>,
+    pub config: Option<PathBuf>,
+    pub log_level: Option<String>,
+    pub max_payload: Option<usize>,
+    pub pid_file: Option<PathBuf>,
+    pub help: bool,
+    pub version: bool,
+}
+
+const LOG_LEVELS: [&str; 6] = ["off", "error", "warn", "info", "debug", "trace"];
+// 需要参数值的选项
+const VALUE_OPTIONS: [&str; 10] = [
+    "-a",
+    "--addr",
+    "-p",
+    "--port",
+    "-c",
+    "--config",
+    "-l",
+    "--log-level",
+    "--max-payload",
+    "--pid-file",
+];
+
+impl Options {
+    /**
+     * 解析命令行参数,不包括程序名,参数值可以是 --port 4222 或者 --port=4222
+     */
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Self, String> {
+        let mut opts = Options::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let (name, inline_value) = match arg.split_once('=') {
+                Some((name, value)) if name.starts_with("--") => (name, Some(value.to_string())),
+                _ => (arg.as_str(), None),
+            };
+            match name {
+                "-h" | "--help" => opts.help = true,
+                "-v" | "--version" => opts.version = true,
+                _ if !VALUE_OPTIONS.contains(&name) => {
+                    return Err(format!("unknown option {}", name))
+                }
+                _ => {
+                    let value = match inline_value.or_else(|| args.next()) {
+                        Some(value) => value,
+                        None => return Err(format!("missing value for {}", name)),
+                    };
+                    opts.set(name, value)?;
+                }
+            }
+        }
+        Ok(opts)
+    }
+
+    fn set(&mut self, name: &str, value: String) -> Result<(), String> {
+        let invalid = || format!("invalid value for {}: {}", name, value);
+        match name {
+            "-a" | "--addr" => self.addr = Some(value),
+            "-p" | "--port" => self.port = Some(value.parse().map_err(|_| invalid())?),
+            "-c" | "--config" => self.config = Some(PathBuf::from(value)),
+            "-l" | "--log-level" => {
+                if !LOG_LEVELS.contains(&value.to_ascii_lowercase().as_str()) {
+                    return Err(invalid());
+                }
+                self.log_level = Some(value.to_ascii_lowercase())
+            }
+            "--max-payload" => self.max_payload = Some(parse_size(&value).ok_or_else(invalid)?),
+            "--pid-file" => self.pid_file = Some(PathBuf::from(value)),
+            _ => return Err(format!("unknown option {}", name)),
+        }
+        Ok(())
+    }
+
+    // 用命令行中指定的参数覆盖配置
+    pub fn apply(&self, config: &mut ServerConfig) {
+        if let Some(ref addr) = self.addr {
+            config.host = addr.clone();
+        }
+        if let Some(port) = self.port {
+            config.port = port;
+        }
+        if let Some(ref log_level) = self.log_level {
+            config.log_level = log_level.clone();
+        }
+        if let Some(max_payload) = self.max_payload {
+            config.max_payload = max_payload;
+        }
+        if let Some(ref pid_file) = self.pid_file {
+            config.pid_file = Some(pid_file.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        Options::parse(args.iter().map(|s| s.to_string()))
+    }
+
+    #[test]
+    fn test_parse() {
+        let opts = parse(&[
+            "-c",
+            "server.conf",
+            "--port=4222",
+            "--addr",
+            "0.0.0.0",
+            "-l",
+            "DEBUG",
+            "--max-payload",
+            "2MB",
+            "--pid-file",
+            "/tmp/msgnats.pid",
+        ])
+        .unwrap();
+        assert_eq!(opts.config, Some(PathBuf::from("server.conf")));
+        assert_eq!(opts.port, Some(4222));
+        assert_eq!(opts.log_level.as_deref(), Some("debug"));
+        assert_eq!(opts.max_payload, Some(2 * 1024 * 1024));
+        assert!(!opts.help);
+        assert!(parse(&["-h"]).unwrap().help);
+        assert!(parse(&["--version"]).unwrap().version);
+
+        let mut config = ServerConfig {
+            port: 1,
+            max_subscriptions: 10,
+            ..Default::default()
+        };
+        opts.apply(&mut config);
+        assert_eq!(config.addr(), "0.0.0.0:4222");
+        assert_eq!(config.max_payload, 2 * 1024 * 1024);
+        assert_eq!(config.max_subscriptions, 10);
+        assert_eq!(config.log_level, "debug");
+        assert_eq!(config.pid_file, Some(PathBuf::from("/tmp/msgnats.pid")));
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert_eq!(parse(&["--port"]).unwrap_err(), "missing value for --port");
+        assert_eq!(
+            parse(&["--port", "abc"]).unwrap_err(),
+            "invalid value for --port: abc"
+        );
+        assert_eq!(
+            parse(&["--max-payload=1X"]).unwrap_err(),
+            "invalid value for --max-payload: 1X"
+        );
+        assert!(parse(&["-l", "verbose"]).is_err());
+        assert_eq!(parse(&["--foo", "1"]).unwrap_err(), "unknown option --foo");
+        assert_eq!(parse(&["--foo"]).unwrap_err(), "unknown option --foo");
+    }
+}
@@ -1,53 +1,1328 @@
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 
-use tokio::io::{AsyncWriteExt, WriteHalf};
-use tokio::net::TcpStream;
+use log::{debug, warn};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
 use tokio::sync::Mutex;
 
+use crate::account::GLOBAL_ACCOUNT;
+use crate::auth::Permissions;
+use crate::config::ServerConfig;
+use crate::errors::{
+    NError, Result, ERROR_AUTHENTICATION_TIMEOUT, ERROR_AUTHORIZATION_VIOLATION,
+    ERROR_CONNECTION_CLOSED, ERROR_MAX_SUBSCRIPTIONS, ERROR_PUBLISH_VIOLATION, ERROR_SLOW_CONSUMER,
+    ERROR_STALE_CONNECTION, ERROR_SUBSCRIPTION_VIOLATION,
+};
+use crate::headers::{HeaderMap, HeaderPolicy};
+use crate::info::ConnectOptions;
+use crate::parser::{ParseResult, Parser, PubArg, SubArg, UnsubArg};
+use crate::queue::QueueSelector;
 use crate::server::ServerState;
-use crate::simple_sublist::SubListTrait;
+use crate::simple_sublist::{
+    ArcSubscription, ConnKind, Delivery, SubListTrait, SubResult, SubScription,
+};
+use crate::trie_sublist::validate_literal_subject;
+
+// 每次从连接中读取数据的缓冲区大小
+const READ_BUF_LEN: usize = 64 * 1024;
 
 /**
  * 定义client
+ * 每个连接对应一个client,由独立的task负责读取和分发消息
  */
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct Client<T: SubListTrait> {
-    sub_list: T,
+    pub cid: u64,
+    pub serv_state: Arc<Mutex<ServerState<T>>>,
+    pub msg_sender: Arc<Mutex<ClientMessageSender>>,
+    config: Arc<ServerConfig>,              // 连接建立时的服务端配置
+    subs: HashMap<String, ArcSubscription>, // 该连接上的订阅 sid -> subscription
+    pings_out: usize,                       // 已发送但是还没有收到PONG的PING数量
+    pub connect_options: ConnectOptions,    // 客户端CONNECT时携带的选项
+    authorized: bool,                       // 是否已经通过认证,不需要认证时一开始就是true
+    permissions: Arc<Permissions>,          // 认证通过后这个连接可以发布和订阅的主题
+    account: String,                        // 客户端所属的账户,只能访问这个账户的订阅
+    identities: Vec<String>,                // TLS客户端证书中的身份,用于verify_and_map认证
 }
 
-impl<T: SubListTrait + Send + 'static> Client<T> {
-    pub fn process_connection(
+impl<T: SubListTrait + Default + Send + 'static> Client<T> {
+    /**
+     * 拆分连接,启动读取任务,返回写端供其他client推送消息
+     * conn可以是TCP连接或者TLS握手完成后的连接
+     */
+    pub fn process_connection<S: AsyncRead + AsyncWrite + Send + 'static>(
         cid: u64,
         serv_state: Arc<Mutex<ServerState<T>>>,
-        conn: TcpStream,
+        config: Arc<ServerConfig>,
+        conn: S,
+        identities: Vec<String>,
     ) -> Arc<Mutex<ClientMessageSender>> {
-        todo!()
+        let (reader, writer) = tokio::io::split(conn);
+        let (event_tx, event_rx) = mpsc::unbounded_channel();
+        let mut msg_sender = ClientMessageSender::new(writer);
+        msg_sender.event_tx = Some(event_tx);
+        msg_sender.write_deadline = Some(config.write_deadline);
+        let permissions = Arc::new(config.authorization.default_permissions.clone());
+        msg_sender.permissions = permissions.clone();
+        let msg_sender = Arc::new(Mutex::new(msg_sender));
+        let client = Client {
+            cid,
+            serv_state,
+            msg_sender: msg_sender.clone(),
+            subs: HashMap::new(),
+            pings_out: 0,
+            connect_options: ConnectOptions::default(),
+            authorized: !config.auth_required(),
+            permissions,
+            account: GLOBAL_ACCOUNT.to_string(),
+            identities,
+            config,
+        };
+        tokio::spawn(client.client_task(reader, event_rx));
+        msg_sender
+    }
+
+    // 读取数据 -> 解析 -> 处理sub/pub/unsub,连接断开或者出现致命错误后清理
+    // 同时接收其他client投递时发现的已达到max_msgs的订阅,由自己负责删除
+    // 以及其他client推送超时发现的慢消费者,直接断开连接
+    // 并且定时PING客户端,超过max_pings_out个PING没有回复就断开连接
+    // 需要认证时,超过认证超时时间还没有通过认证也断开连接
+    async fn client_task<S: AsyncRead>(
+        mut self,
+        mut reader: ReadHalf<S>,
+        mut event_rx: UnboundedReceiver<ClientEvent>,
+    ) {
+        let mut buf = vec![0u8; READ_BUF_LEN];
+        let mut parser = Parser::with_limits(self.config.max_control_line, self.config.max_payload);
+        let ping_interval = self.config.ping_interval;
+        let max_pings_out = self.config.max_pings_out;
+        let mut ping_timer =
+            tokio::time::interval_at(tokio::time::Instant::now() + ping_interval, ping_interval);
+        if self.authorized {
+            self.bind_account(GLOBAL_ACCOUNT).await;
+        }
+        let auth_timer = tokio::time::sleep(self.config.authorization.timeout);
+        tokio::pin!(auth_timer);
+        loop {
+            let r = tokio::select! {
+                _ = &mut auth_timer, if !self.authorized => {
+                    Err(NError::new(ERROR_AUTHENTICATION_TIMEOUT))
+                }
+                _ = ping_timer.tick() => self.send_ping(max_pings_out).await,
+                r = reader.read(&mut buf[..]) => match r {
+                    Ok(0) => break,
+                    Ok(n) => self.process_buf(&mut parser, &buf[..n]).await,
+                    Err(e) => {
+                        warn!("client {} read error:{}", self.cid, e);
+                        break;
+                    }
+                },
+                Some(event) = event_rx.recv() => match event {
+                    ClientEvent::Expired(sub) => self.process_expired(sub).await,
+                    ClientEvent::SlowConsumer => Err(NError::new(ERROR_SLOW_CONSUMER)),
+                    ClientEvent::Close => break,
+                    ClientEvent::Send(data) => self.send_raw(&data).await,
+                },
+            };
+            if let Err(e) = r {
+                debug!("client {} closing, error:{}", self.cid, e);
+                self.send_err(&e).await;
+                break;
+            }
+        }
+        self.close().await;
+    }
+
+    // 解析出错时解析器状态已经无法恢复,直接返回错误关闭连接
+    // 处理某条消息出错时,非致命错误只回复-ERR,继续处理后面的消息
+    async fn process_buf(&mut self, parser: &mut Parser, mut buf: &[u8]) -> Result<()> {
+        while !buf.is_empty() {
+            let (result, n) = parser.parse(buf)?;
+            buf = &buf[n..];
+            // 需要认证时第一条消息必须是CONNECT
+            if !self.authorized
+                && !matches!(result, ParseResult::NoMsg | ParseResult::ConnectArg(_))
+            {
+                return Err(NError::new(ERROR_AUTHORIZATION_VIOLATION));
+            }
+            // PING的回复就是PONG,不需要+OK
+            let need_ok = !matches!(result, ParseResult::Ping);
+            let r = match result {
+                ParseResult::NoMsg => continue,
+                ParseResult::Ping => self.send_raw(b"PONG\r\n").await,
+                ParseResult::Pong => {
+                    self.pings_out = 0;
+                    continue;
+                }
+                ParseResult::SubArg(ref sub_arg) => self.process_sub(sub_arg).await,
+                ParseResult::PubArg(ref pub_arg) => self.process_pub(pub_arg).await,
+                ParseResult::UnsubArg(ref unsub_arg) => self.process_unsub(unsub_arg).await,
+                ParseResult::ConnectArg(opts) => self.process_connect(opts).await,
+            };
+            match r {
+                Ok(()) => {
+                    if need_ok && self.connect_options.verbose {
+                        self.send_raw(b"+OK\r\n").await?;
+                    }
+                }
+                Err(e) if !e.is_fatal() => self.send_err(&e).await,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    // 认证失败是致命错误,回复-ERR后断开连接
+    async fn process_connect(&mut self, opts: ConnectOptions) -> Result<()> {
+        if !self.authorized {
+            // bcrypt校验很耗时,放到阻塞线程池中执行,避免占用异步运行时的工作线程
+            let config = self.config.clone();
+            let identities = self.identities.clone();
+            let user_pass = opts.clone();
+            let auth = tokio::task::spawn_blocking(move || {
+                // verify_and_map时只使用证书中的身份,忽略user/pass
+                let auth = if config.verify_and_map() {
+                    config.authenticate_identities(&identities)
+                } else {
+                    config.authenticate(&user_pass)
+                };
+                auth.map(|(account, permissions)| {
+                    (account.to_string(), Arc::new(permissions.clone()))
+                })
+            })
+            .await
+            .unwrap_or(None);
+            let (account, permissions) = match auth {
+                Some(auth) => auth,
+                None => {
+                    warn!("client {} authorization violation", self.cid);
+                    return Err(NError::new(ERROR_AUTHORIZATION_VIOLATION));
+                }
+            };
+            self.msg_sender.lock().await.permissions = permissions.clone();
+            self.permissions = permissions;
+            self.authorized = true;
+            self.bind_account(&account).await;
+        }
+        self.msg_sender.lock().await.headers = opts.headers;
+        self.connect_options = opts;
+        Ok(())
+    }
+
+    // 认证通过后加入账户,之后的订阅和发布都在这个账户中
+    async fn bind_account(&mut self, account: &str) {
+        debug!("client {} bind to account {}", self.cid, account);
+        let mut state = self.serv_state.lock().await;
+        state.account_mut(account).clients.insert(self.cid);
+        self.account = account.to_string();
+    }
+
+    // 发送PING,超过max_pings_out个PING没有回复认为连接已经失效
+    async fn send_ping(&mut self, max_pings_out: usize) -> Result<()> {
+        if self.pings_out >= max_pings_out {
+            return Err(NError::new(ERROR_STALE_CONNECTION));
+        }
+        self.pings_out += 1;
+        self.send_raw(b"PING\r\n").await
+    }
+
+    // 把错误原因告诉客户端,连接已经断开的就不需要了
+    async fn send_err(&self, e: &NError) {
+        if e.err_code() != ERROR_CONNECTION_CLOSED {
+            let _ = self.send_raw(&e.to_protocol()).await;
+        }
+    }
+
+    // 向自己的连接写协议数据,写失败说明连接已经不可用
+    async fn send_raw(&self, data: &[u8]) -> Result<()> {
+        self.msg_sender
+            .lock()
+            .await
+            .send_raw(data)
+            .await
+            .map_err(|_| NError::new(ERROR_CONNECTION_CLOSED))
+    }
+
+    async fn process_sub(&mut self, sub_arg: &SubArg<'_>) -> Result<()> {
+        if !self.permissions.subscribe.permits(sub_arg.subject) {
+            return Err(NError::with_subject(
+                ERROR_SUBSCRIPTION_VIOLATION,
+                sub_arg.subject,
+            ));
+        }
+        let mut state = self.serv_state.lock().await;
+        // 重复的sid只是替换原来的订阅,不增加订阅数量
+        if !self.subs.contains_key(sub_arg.sid) {
+            let max_subscriptions = self.config.max_subscriptions;
+            if max_subscriptions > 0 && self.subs.len() >= max_subscriptions {
+                warn!("client {} maximum subscriptions exceeded", self.cid);
+                state.stats.max_subscriptions_exceeded += 1;
+                return Err(NError::new(ERROR_MAX_SUBSCRIPTIONS));
+            }
+            let account_config = self.config.accounts.get(&self.account);
+            let max_subscriptions = account_config.map_or(0, |a| a.max_subscriptions);
+            if max_subscriptions > 0
+                && state.account_mut(&self.account).client_subs >= max_subscriptions
+            {
+                warn!(
+                    "client {} account {} maximum subscriptions exceeded",
+                    self.cid, self.account
+                );
+                state.stats.account_max_subscriptions_exceeded += 1;
+                return Err(NError::new(ERROR_MAX_SUBSCRIPTIONS));
+            }
+        }
+        let sub = Arc::new(SubScription::new(
+            self.msg_sender.clone(),
+            sub_arg.subject,
+            sub_arg.queue,
+            sub_arg.sid,
+        ));
+        state.insert_sub(&self.account, sub.clone())?;
+        // 相同sid重复订阅,旧的订阅需要从sub_list中移除
+        if let Some(old) = self.subs.insert(sub.sid.clone(), sub) {
+            state.remove_sub(&self.account, old)?;
+        }
+        Ok(())
+    }
+
+    async fn process_pub(&self, pub_arg: &PubArg<'_>) -> Result<()> {
+        if !self.permissions.publish.permits(pub_arg.subject) {
+            return Err(NError::with_subject(
+                ERROR_PUBLISH_VIOLATION,
+                pub_arg.subject,
+            ));
+        }
+        if let Some(reply_to) = pub_arg.reply_to {
+            validate_literal_subject(reply_to)?;
+        }
+        if let Some(headers) = pub_arg.headers() {
+            HeaderMap::parse(headers)?;
+        }
+        publish(&self.serv_state, &self.account, pub_arg).await
+    }
+
+    // 取消订阅,带max_msgs时等投递够数量后再自动删除,max_msgs为0和不带一样立即删除
+    async fn process_unsub(&mut self, unsub_arg: &UnsubArg<'_>) -> Result<()> {
+        let expired = match (self.subs.get(unsub_arg.sid), unsub_arg.max_msgs) {
+            (None, _) => return Ok(()), // 不存在的sid直接忽略
+            (Some(sub), Some(max_msgs)) if max_msgs > 0 => sub.set_max_msgs(max_msgs),
+            (Some(_), _) => true,
+        };
+        if expired {
+            self.remove_sub(unsub_arg.sid).await?;
+        }
+        Ok(())
+    }
+
+    // 只删除仍然是同一个订阅的sid,避免误删后来复用了这个sid的订阅
+    async fn process_expired(&mut self, sub: ArcSubscription) -> Result<()> {
+        if matches!(self.subs.get(&sub.sid), Some(s) if Arc::ptr_eq(s, &sub)) {
+            self.remove_sub(&sub.sid).await?;
+        }
+        Ok(())
+    }
+
+    async fn remove_sub(&mut self, sid: &str) -> Result<()> {
+        if let Some(sub) = self.subs.remove(sid) {
+            let mut state = self.serv_state.lock().await;
+            state.remove_sub(&self.account, sub)?;
+        }
+        Ok(())
+    }
+
+    // 连接断开,删除该client的所有订阅以及server中的记录
+    async fn close(&mut self) {
+        {
+            let mut state = self.serv_state.lock().await;
+            for (_, sub) in self.subs.drain() {
+                if let Err(e) = state.remove_sub(&self.account, sub) {
+                    warn!("client {} remove sub error:{}", self.cid, e);
+                }
+            }
+            state.account_mut(&self.account).clients.remove(&self.cid);
+            state.clients.remove(&self.cid);
+        }
+        self.msg_sender.lock().await.close().await;
+    }
+}
+
+/**
+ * 把account中的一条消息投递给所有订阅者,客户端发布的消息以及服务端内部发布的消息都经过这里
+ */
+pub async fn publish<T: SubListTrait + Default>(
+    serv_state: &Mutex<ServerState<T>>,
+    account: &str,
+    pub_arg: &PubArg<'_>,
+) -> Result<()> {
+    publish_from(serv_state, account, pub_arg, &Source::Client).await
+}
+
+/**
+ * 和publish相同,source是消息的来源,比如叶子节点发布的消息不再转发回这个叶子节点
+ */
+pub async fn publish_from<T: SubListTrait + Default>(
+    serv_state: &Mutex<ServerState<T>>,
+    account: &str,
+    pub_arg: &PubArg<'_>,
+    source: &Source<'_>,
+) -> Result<()> {
+    // 查找订阅后立即释放server锁,推送消息时不持有
+    let (routes, header_policy, queue_selector) = {
+        let mut state = serv_state.lock().await;
+        (
+            state.route(account, pub_arg.subject, pub_arg.reply_to)?,
+            state.config.header_policy,
+            state.queue_selector.clone(),
+        )
+    };
+    for (i, route) in routes.iter().enumerate() {
+        // 投递到其他账户相当于在那个账户中重新发布,来源列出的queue group只适用于原来的账户
+        let source = if i == 0 { source } else { &Source::Client };
+        // 导入导出的消息在其他账户中使用映射后的主题
+        let pub_arg = PubArg {
+            subject: &route.subject,
+            reply_to: route.reply_to.as_deref(),
+            ..*pub_arg
+        };
+        let mut forwards = deliver(
+            &route.account,
+            &route.result,
+            &pub_arg,
+            header_policy,
+            queue_selector.as_ref(),
+            source,
+        )
+        .await;
+        forward_gateways(&mut forwards, &route.gateways);
+        send_forwards(&route.account, &pub_arg, &forwards).await;
     }
+    Ok(())
 }
 
+/**
+ * 需要转发给其他服务端的消息,同一个路由只转发一次
+ */
 #[derive(Debug)]
+pub struct Forward {
+    pub sender: Arc<Mutex<ClientMessageSender>>, // 路由、叶子节点或者网关连接的写端
+    pub queues: Vec<String>,                     // 由对方投递给其中一个订阅者的queue group
+}
+
+/**
+ * 消息的来源,决定消息还可以转发给哪些连接
+ */
+pub enum Source<'a> {
+    Client,                                                  // 本服务端的客户端,可以转发给所有连接
+    Route(&'a [String]),   // 路由,只转发给叶子节点,queue group只投递列出的这些
+    Gateway(&'a [String]), // 其他集群,转发给本集群的路由和叶子节点
+    Leaf(&'a Arc<Mutex<ClientMessageSender>>, &'a [String]), // 叶子节点,不再转发回这个连接
+}
+
+impl Source<'_> {
+    fn permits_queue(&self, queue: &str) -> bool {
+        match self {
+            Source::Client => true,
+            Source::Route(queues) | Source::Gateway(queues) | Source::Leaf(_, queues) => {
+                queues.iter().any(|q| q == queue)
+            }
+        }
+    }
+
+    // 路由收到的消息不会再转发给其他路由,网关收到的消息不会再转发给其他集群,也不会转发回消息来源
+    fn permits_forward(&self, sub: &ArcSubscription) -> bool {
+        match self {
+            Source::Client => true,
+            Source::Route(_) => sub.kind == ConnKind::Leaf,
+            Source::Gateway(_) => sub.kind != ConnKind::Gateway,
+            Source::Leaf(sender, _) => !Arc::ptr_eq(sender, &sub.msg_sender),
+        }
+    }
+}
+
+/**
+ * 投递一条消息给匹配到的订阅,返回需要转发给其他服务端的消息
+ * 普通订阅在其他服务端时转发给对应的连接,
+ * queue group优先投递给本地的订阅者,其次是本集群的其他服务端,最后才是其他集群,由对方投递;
+ * 消息来自其他服务端时只投递对方列出的queue group
+ */
+pub async fn deliver(
+    account: &str,
+    result: &SubResult,
+    pub_arg: &PubArg<'_>,
+    header_policy: HeaderPolicy,
+    queue_selector: &dyn QueueSelector,
+    source: &Source<'_>,
+) -> Vec<Forward> {
+    let mut forwards: Vec<Forward> = Vec::new();
+    for sub in result.ppubs.iter() {
+        if !sub.is_remote() {
+            send_message(sub, pub_arg, header_policy).await;
+        } else if source.permits_forward(sub) {
+            forward_to(&mut forwards, sub, None);
+        }
+    }
+    for qsubs in result.qpubs.iter().filter(|q| !q.is_empty()) {
+        let queue = qsubs[0].queue.as_deref().unwrap_or_default();
+        if !source.permits_queue(queue) {
+            continue;
+        }
+        let (remote, local): (Vec<_>, Vec<_>) = qsubs
+            .iter()
+            .filter(|s| !s.is_remote() || source.permits_forward(s))
+            .cloned()
+            .partition(|s| s.is_remote());
+        let (gateways, cluster): (Vec<_>, Vec<_>) = remote
+            .into_iter()
+            .partition(|s| s.kind == ConnKind::Gateway);
+        // 每个queue group只投递给其中一个订阅者,从选中的订阅者开始依次尝试
+        let mut delivered = false;
+        if !local.is_empty() {
+            let start = queue_selector.select(account, &local);
+            for i in 0..local.len() {
+                if send_message(&local[(start + i) % local.len()], pub_arg, header_policy).await {
+                    delivered = true;
+                    break;
+                }
+            }
+        }
+        if delivered {
+            continue;
+        }
+        if let Some(remote) = [cluster, gateways].into_iter().find(|r| !r.is_empty()) {
+            let sub = &remote[queue_selector.select(account, &remote)];
+            forward_to(&mut forwards, sub, Some(queue));
+        }
+    }
+    forwards
+}
+
+/**
+ * 乐观模式的网关没有订阅也要发送,已经因为订阅转发过的不再重复发送
+ */
+pub fn forward_gateways(forwards: &mut Vec<Forward>, gateways: &[Arc<Mutex<ClientMessageSender>>]) {
+    for sender in gateways {
+        if !forwards.iter().any(|f| Arc::ptr_eq(&f.sender, sender)) {
+            forwards.push(Forward {
+                sender: sender.clone(),
+                queues: Vec::new(),
+            });
+        }
+    }
+}
+
+/**
+ * 把deliver返回的消息转发出去,account是消息所在的账户
+ */
+pub async fn send_forwards(account: &str, pub_arg: &PubArg<'_>, forwards: &[Forward]) {
+    for forward in forwards.iter() {
+        let mut sender = forward.sender.lock().await;
+        // 叶子节点连接带有hub一端用户的权限,通配符订阅中被拒绝的主题不转发
+        if sender.permissions.subscribe.denies(pub_arg.subject) {
+            continue;
+        }
+        if let Err(e) = sender
+            .send_route_message(account, pub_arg, &forward.queues)
+            .await
+        {
+            debug!("forward message error:{}", e);
+        }
+    }
+}
+
+fn forward_to(forwards: &mut Vec<Forward>, sub: &ArcSubscription, queue: Option<&str>) {
+    let forward = match forwards
+        .iter_mut()
+        .position(|f| Arc::ptr_eq(&f.sender, &sub.msg_sender))
+    {
+        Some(i) => &mut forwards[i],
+        None => {
+            forwards.push(Forward {
+                sender: sub.msg_sender.clone(),
+                queues: Vec::new(),
+            });
+            forwards.last_mut().unwrap()
+        }
+    };
+    if let Some(queue) = queue {
+        forward.queues.push(queue.to_string());
+    }
+}
+
+// 某个订阅者写失败不影响发布者和其他订阅者,由订阅者自己的读取任务负责清理
+// 返回false表示订阅已经达到max_msgs、不接受消息头或者没有订阅这个主题的权限,没有投递
+async fn send_message(
+    sub: &ArcSubscription,
+    pub_arg: &PubArg<'_>,
+    header_policy: HeaderPolicy,
+) -> bool {
+    let mut msg_sender = sub.msg_sender.lock().await;
+    if pub_arg.hdr_len.is_some() && !msg_sender.headers && header_policy == HeaderPolicy::Reject {
+        return false;
+    }
+    if msg_sender.permissions.subscribe.denies(pub_arg.subject) {
+        return false;
+    }
+    let delivery = sub.acquire_delivery();
+    if delivery == Delivery::Skip {
+        return false;
+    }
+    if let Err(e) = msg_sender.send_message(&sub.sid, pub_arg).await {
+        debug!("send message to sid {} error:{}", sub.sid, e);
+    }
+    if delivery == Delivery::Last {
+        msg_sender.notify_expired(sub.clone());
+    }
+    true
+}
+
+// 连接的写端,不关心底层具体是什么连接
+pub type BoxedWriter = Box<dyn AsyncWrite + Send + Unpin>;
+
+/**
+ * 其他client推送消息时发现的需要连接所属client处理的事件
+ */
+#[derive(Debug)]
+pub enum ClientEvent {
+    Expired(ArcSubscription), // 订阅已经达到max_msgs,需要删除
+    SlowConsumer,             // 推送超时,需要断开连接
+    Close,                    // 服务端关闭,需要断开连接
+    Send(Vec<u8>),            // 持有server锁时需要发送的协议数据,由连接自己的任务按顺序写入
+}
+
+/**
+ * 服务端内部的订阅收到的消息,比如stream
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct InternalMessage {
+    pub sid: String,
+    pub subject: String,
+    pub reply_to: Option<String>,
+    pub hdr_len: Option<usize>,
+    pub msg: Vec<u8>, // 带消息头时消息头在最前面
+}
+
 pub struct ClientMessageSender {
-    writer: Option<WriteHalf<TcpStream>>,
+    writer: Option<BoxedWriter>,
     msg_buf: Option<Vec<u8>>,
+    pub(crate) event_tx: Option<UnboundedSender<ClientEvent>>, // 通知连接所属的client
+    pub(crate) internal_tx: Option<UnboundedSender<InternalMessage>>, // 内部订阅,消息不写入连接
+    pub write_deadline: Option<Duration>,                      // 写超时时间,超时认为是慢消费者
+    pub permissions: Arc<Permissions>,                         // 通配符订阅投递时检查主题是否被拒绝
+    pub headers: bool, // 客户端CONNECT时声明支持消息头,可以接收HMSG
+    pub leaf: bool,    // 叶子节点连接,转发的消息使用LMSG并且不带账户
+}
+
+impl std::fmt::Debug for ClientMessageSender {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClientMessageSender")
+            .field("closed", &self.writer.is_none())
+            .field("msg_buf", &self.msg_buf)
+            .finish()
+    }
 }
 
 impl ClientMessageSender {
-    pub fn new(writer: WriteHalf<TcpStream>) -> Self {
+    pub fn new<W: AsyncWrite + Send + Unpin + 'static>(writer: W) -> Self {
         Self {
-            writer: Some(writer),
+            writer: Some(Box::new(writer)),
             msg_buf: Some(Vec::with_capacity(512)), // 初始缓冲区大小 512
+            event_tx: None,
+            internal_tx: None,
+            write_deadline: None,
+            permissions: Arc::default(),
+            headers: false,
+            leaf: false,
+        }
+    }
+
+    pub fn notify_expired(&self, sub: ArcSubscription) {
+        self.notify(ClientEvent::Expired(sub));
+    }
+
+    // 通知client清理订阅并关闭连接
+    pub fn notify_close(&self) {
+        self.notify(ClientEvent::Close);
+    }
+
+    fn notify(&self, event: ClientEvent) {
+        if let Some(ref tx) = self.event_tx {
+            // client已经退出时,订阅会在close中统一删除
+            let _ = tx.send(event);
+        }
+    }
+
+    /**
+     * 推送消息给订阅者
+     * MSG <subject> <sid> [reply-to] <size>\r\n
+     * <message>\r\n
+     * 订阅者支持消息头时,带消息头的消息推送HMSG,否则只推送消息体
+     * HMSG <subject> <sid> [reply-to] <hdr_len> <total_len>\r\n
+     * <headers><message>\r\n
+     */
+    pub async fn send_message(&mut self, sid: &str, pub_arg: &PubArg<'_>) -> std::io::Result<()> {
+        if let Some(ref tx) = self.internal_tx {
+            // 内部订阅的处理任务已经退出时直接丢弃
+            let _ = tx.send(InternalMessage {
+                sid: sid.to_string(),
+                subject: pub_arg.subject.to_string(),
+                reply_to: pub_arg.reply_to.map(|r| r.to_string()),
+                hdr_len: pub_arg.hdr_len,
+                msg: pub_arg.msg.to_vec(),
+            });
+            return Ok(());
+        }
+        let with_headers = pub_arg.hdr_len.is_some() && self.headers;
+        let msg_buf = self.msg_buf.as_mut().unwrap();
+        msg_buf.extend_from_slice(if with_headers { b"HMSG " } else { b"MSG " });
+        msg_buf.extend_from_slice(pub_arg.subject.as_bytes());
+        msg_buf.push(b' ');
+        msg_buf.extend_from_slice(sid.as_bytes());
+        msg_buf.push(b' ');
+        if let Some(reply_to) = pub_arg.reply_to {
+            msg_buf.extend_from_slice(reply_to.as_bytes());
+            msg_buf.push(b' ');
         }
+        let msg = match pub_arg.hdr_len {
+            Some(hdr_len) if with_headers => {
+                msg_buf.extend_from_slice(hdr_len.to_string().as_bytes());
+                msg_buf.push(b' ');
+                msg_buf.extend_from_slice(pub_arg.size_buf.as_bytes());
+                pub_arg.msg
+            }
+            Some(_) => {
+                let payload = pub_arg.payload();
+                msg_buf.extend_from_slice(payload.len().to_string().as_bytes());
+                payload
+            }
+            None => {
+                msg_buf.extend_from_slice(pub_arg.size_buf.as_bytes());
+                pub_arg.msg
+            }
+        };
+        msg_buf.extend_from_slice(b"\r\n");
+        msg_buf.extend_from_slice(msg);
+        msg_buf.extend_from_slice(b"\r\n");
+        self.send_all().await
     }
 
+    /**
+     * 转发消息给路由,queues是需要对方投递的queue group
+     * RMSG <account> <subject> [+ <reply-to> <queues> | | <queues>] <size>\r\n
+     * 带消息头时
+     * HMSG <account> <subject> [+ <reply-to> <queues> | | <queues>] <hdr_len> <total_len>\r\n
+     * 叶子节点连接绑定了账户,使用LMSG并且省略account,带消息头时仍然是HMSG
+     */
+    pub async fn send_route_message(
+        &mut self,
+        account: &str,
+        pub_arg: &PubArg<'_>,
+        queues: &[String],
+    ) -> std::io::Result<()> {
+        let msg_buf = self.msg_buf.as_mut().unwrap();
+        msg_buf.extend_from_slice(match (pub_arg.hdr_len.is_some(), self.leaf) {
+            (true, _) => b"HMSG ",
+            (false, true) => b"LMSG ",
+            (false, false) => b"RMSG ",
+        });
+        if !self.leaf {
+            msg_buf.extend_from_slice(account.as_bytes());
+            msg_buf.push(b' ');
+        }
+        msg_buf.extend_from_slice(pub_arg.subject.as_bytes());
+        msg_buf.push(b' ');
+        match (pub_arg.reply_to, queues.is_empty()) {
+            (Some(reply_to), true) => {
+                msg_buf.extend_from_slice(reply_to.as_bytes());
+                msg_buf.push(b' ');
+            }
+            (Some(reply_to), false) => {
+                msg_buf.extend_from_slice(b"+ ");
+                msg_buf.extend_from_slice(reply_to.as_bytes());
+                msg_buf.push(b' ');
+            }
+            (None, false) => msg_buf.extend_from_slice(b"| "),
+            (None, true) => {}
+        }
+        for queue in queues {
+            msg_buf.extend_from_slice(queue.as_bytes());
+            msg_buf.push(b' ');
+        }
+        if let Some(hdr_len) = pub_arg.hdr_len {
+            msg_buf.extend_from_slice(hdr_len.to_string().as_bytes());
+            msg_buf.push(b' ');
+        }
+        msg_buf.extend_from_slice(pub_arg.size_buf.as_bytes());
+        msg_buf.extend_from_slice(b"\r\n");
+        msg_buf.extend_from_slice(pub_arg.msg);
+        msg_buf.extend_from_slice(b"\r\n");
+        self.send_all().await
+    }
+
+    // 直接推送协议数据,比如PING/PONG
+    pub async fn send_raw(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.msg_buf.as_mut().unwrap().extend_from_slice(data);
+        self.send_all().await
+    }
+
+    // 超过write_deadline还没有写完认为是慢消费者,丢弃写端并通知client断开连接
     pub async fn send_all(&mut self) -> std::io::Result<()> {
-        if let Some(ref mut writer) = self.writer {
-            let r = writer
-                .write_all(self.msg_buf.as_ref().unwrap().as_slice())
-                .await;
-            self.msg_buf.as_mut().unwrap().clear(); //清空数据
-            r
+        let r = if let Some(ref mut writer) = self.writer {
+            let data = self.msg_buf.as_ref().unwrap().as_slice();
+            match self.write_deadline {
+                Some(deadline) => tokio::time::timeout(deadline, writer.write_all(data))
+                    .await
+                    .unwrap_or_else(|_| {
+                        Err(std::io::Error::new(
+                            std::io::ErrorKind::TimedOut,
+                            "write deadline exceeded",
+                        ))
+                    }),
+                None => writer.write_all(data).await,
+            }
         } else {
             Ok(())
+        };
+        self.msg_buf.as_mut().unwrap().clear(); //清空数据
+        if matches!(r, Err(ref e) if e.kind() == std::io::ErrorKind::TimedOut) {
+            self.writer = None;
+            self.notify(ClientEvent::SlowConsumer);
+        }
+        r
+    }
+
+    // 关闭写端,之后的推送都会被忽略
+    pub async fn close(&mut self) {
+        if let Some(mut writer) = self.writer.take() {
+            let _ = writer.shutdown().await;
         }
+        self.msg_buf.as_mut().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::{RESPONSE_ID_LEN, RESPONSE_PREFIX};
+    use crate::auth::AuthConfig;
+    use crate::queue::QueueStrategy;
+    use crate::simple_sublist::SimpleSubList;
+    use crate::trie_sublist::TrieSubList;
+    use tokio::net::{TcpListener, TcpStream};
+
+    async fn read_until(conn: &mut TcpStream, expect: &[u8]) -> Vec<u8> {
+        let mut received = Vec::new();
+        let mut buf = [0u8; 1024];
+        while received.len() < expect.len() {
+            let n = tokio::time::timeout(std::time::Duration::from_secs(5), conn.read(&mut buf))
+                .await
+                .expect("read timeout")
+                .unwrap();
+            assert!(n > 0, "connection closed early");
+            received.extend_from_slice(&buf[..n]);
+        }
+        received
+    }
+
+    async fn connect<T: SubListTrait + Default + Send + 'static>(
+        state: &Arc<Mutex<ServerState<T>>>,
+    ) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let conn = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server_conn, _) = listener.accept().await.unwrap();
+        let mut s = state.lock().await;
+        s.gen_cid += 1;
+        let cid = s.gen_cid;
+        let sender = Client::process_connection(
+            cid,
+            state.clone(),
+            s.config.clone(),
+            server_conn,
+            Vec::new(),
+        );
+        s.clients.insert(cid, sender);
+        conn
+    }
+
+    #[tokio::test]
+    async fn test_sub_pub_and_close() {
+        let state: Arc<Mutex<ServerState<SimpleSubList>>> = Default::default();
+        let mut conn = connect(&state).await;
+
+        conn.write_all(b"SUB foo 1\r\nSUB foo q 2\r\nPUB foo 5\r\nhello\r\n")
+            .await
+            .unwrap();
+        let expect = b"MSG foo 1 5\r\nhello\r\nMSG foo 2 5\r\nhello\r\n";
+        assert_eq!(read_until(&mut conn, expect).await, expect);
+
+        drop(conn);
+        for _ in 0..100 {
+            if state.lock().await.clients.is_empty() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let s = state.lock().await;
+        assert!(s.clients.is_empty());
+        assert!(s.accounts[GLOBAL_ACCOUNT]
+            .sub_list
+            .match_subject("foo")
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unsub_and_max_msgs() {
+        let state: Arc<Mutex<ServerState<TrieSubList>>> = Default::default();
+        let mut conn = connect(&state).await;
+
+        conn.write_all(b"SUB foo 1\r\nSUB foo 2\r\nUNSUB 1\r\nUNSUB 2 2\r\nUNSUB 9\r\n")
+            .await
+            .unwrap();
+        // UNSUB <sid> 0立即删除订阅
+        conn.write_all(b"SUB foo 4\r\nUNSUB 4 0\r\n").await.unwrap();
+        conn.write_all(b"PUB foo 1\r\na\r\nPUB foo 1\r\nb\r\nPUB foo 1\r\nc\r\n")
+            .await
+            .unwrap();
+        conn.write_all(b"SUB bar 3\r\nPUB bar 1\r\nd\r\n")
+            .await
+            .unwrap();
+        let expect = b"MSG foo 2 1\r\na\r\nMSG foo 2 1\r\nb\r\nMSG bar 3 1\r\nd\r\n";
+        assert_eq!(read_until(&mut conn, expect).await, expect);
+
+        for _ in 0..100 {
+            if state.lock().await.accounts[GLOBAL_ACCOUNT].sub_list.count() == 1 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let s = state.lock().await;
+        assert_eq!(s.accounts[GLOBAL_ACCOUNT].sub_list.count(), 1);
+        assert!(s.accounts[GLOBAL_ACCOUNT]
+            .sub_list
+            .match_subject("foo")
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_ping_pong_and_stale() {
+        let state: Arc<Mutex<ServerState<TrieSubList>>> = Default::default();
+        state.lock().await.config = Arc::new(ServerConfig {
+            ping_interval: Duration::from_millis(50),
+            max_pings_out: 2,
+            ..Default::default()
+        });
+        let mut conn = connect(&state).await;
+        conn.write_all(b"SUB foo 1\r\nPING\r\n").await.unwrap();
+        assert_eq!(read_until(&mut conn, b"PONG\r\n").await, b"PONG\r\n");
+
+        // 回复一次PONG后计数清零,之后不再回复直到连接被关闭
+        assert_eq!(read_until(&mut conn, b"PING\r\n").await, b"PING\r\n");
+        conn.write_all(b"PONG\r\n").await.unwrap();
+        let mut buf = [0u8; 64];
+        let mut pings = 0;
+        loop {
+            let n = tokio::time::timeout(std::time::Duration::from_secs(5), conn.read(&mut buf))
+                .await
+                .expect("read timeout")
+                .unwrap();
+            if n == 0 {
+                break;
+            }
+            pings += buf[..n].windows(4).filter(|w| w == b"PING").count();
+        }
+        assert_eq!(pings, 2);
+        let s = state.lock().await;
+        assert!(s.clients.is_empty());
+        assert_eq!(s.accounts[GLOBAL_ACCOUNT].sub_list.count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_verbose_and_err() {
+        let state: Arc<Mutex<ServerState<TrieSubList>>> = Default::default();
+        let mut conn = connect(&state).await;
+        conn.write_all(b"CONNECT {\"verbose\":true}\r\nSUB a..b 1\r\nSUB a 1\r\nPING\r\n")
+            .await
+            .unwrap();
+        let expect = b"+OK\r\n-ERR 'Invalid Subject'\r\n+OK\r\nPONG\r\n";
+        assert_eq!(read_until(&mut conn, expect).await, expect);
+
+        // 协议错误回复-ERR后关闭连接
+        conn.write_all(b"XSUB a 2\r\n").await.unwrap();
+        let expect = b"-ERR 'Unknown Protocol Operation'\r\n";
+        assert_eq!(read_until(&mut conn, expect).await, expect);
+        let mut buf = [0u8; 16];
+        assert_eq!(conn.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_request_reply() {
+        let state: Arc<Mutex<ServerState<TrieSubList>>> = Default::default();
+        let mut responder = connect(&state).await;
+        let mut requester = connect(&state).await;
+        responder.write_all(b"SUB api.*.get 1\r\n").await.unwrap();
+        requester
+            .write_all(b"SUB _INBOX.abc 9\r\nPING\r\n")
+            .await
+            .unwrap();
+        assert_eq!(read_until(&mut requester, b"PONG\r\n").await, b"PONG\r\n");
+        responder.write_all(b"PING\r\n").await.unwrap();
+        assert_eq!(read_until(&mut responder, b"PONG\r\n").await, b"PONG\r\n");
+
+        requester
+            .write_all(b"PUB api.user.get _INBOX.abc 2\r\nid\r\n")
+            .await
+            .unwrap();
+        let expect = b"MSG api.user.get 1 _INBOX.abc 2\r\nid\r\n";
+        assert_eq!(read_until(&mut responder, expect).await, expect);
+
+        responder
+            .write_all(b"PUB _INBOX.abc 4\r\nuser\r\n")
+            .await
+            .unwrap();
+        let expect = b"MSG _INBOX.abc 9 4\r\nuser\r\n";
+        assert_eq!(read_until(&mut requester, expect).await, expect);
+    }
+
+    #[tokio::test]
+    async fn test_request_reply_empty_payload() {
+        let state: Arc<Mutex<ServerState<TrieSubList>>> = Default::default();
+        let mut responder = connect(&state).await;
+        let mut requester = connect(&state).await;
+        responder
+            .write_all(b"SUB ping 1\r\nPING\r\n")
+            .await
+            .unwrap();
+        assert_eq!(read_until(&mut responder, b"PONG\r\n").await, b"PONG\r\n");
+        requester
+            .write_all(b"SUB _INBOX.abc 9\r\nPUB ping _INBOX.abc 0\r\n\r\n")
+            .await
+            .unwrap();
+        let expect = b"MSG ping 1 _INBOX.abc 0\r\n\r\n";
+        assert_eq!(read_until(&mut responder, expect).await, expect);
+
+        // 空消息之后连接仍然可以继续使用
+        responder
+            .write_all(b"PUB _INBOX.abc 0\r\n\r\nPING\r\n")
+            .await
+            .unwrap();
+        assert_eq!(read_until(&mut responder, b"PONG\r\n").await, b"PONG\r\n");
+        let expect = b"MSG _INBOX.abc 9 0\r\n\r\n";
+        assert_eq!(read_until(&mut requester, expect).await, expect);
+    }
+
+    #[tokio::test]
+    async fn test_headers() {
+        let state: Arc<Mutex<ServerState<TrieSubList>>> = Default::default();
+        let mut with_headers = connect(&state).await;
+        let mut without_headers = connect(&state).await;
+        with_headers
+            .write_all(b"CONNECT {\"headers\":true}\r\nSUB foo 1\r\nPING\r\n")
+            .await
+            .unwrap();
+        assert_eq!(
+            read_until(&mut with_headers, b"PONG\r\n").await,
+            b"PONG\r\n"
+        );
+        without_headers
+            .write_all(b"SUB foo 2\r\nPING\r\n")
+            .await
+            .unwrap();
+        assert_eq!(
+            read_until(&mut without_headers, b"PONG\r\n").await,
+            b"PONG\r\n"
+        );
+
+        with_headers
+            .write_all(b"HPUB foo r 18 20\r\nNATS/1.0\r\nA: 1\r\n\r\nhi\r\n")
+            .await
+            .unwrap();
+        let expect = b"HMSG foo 1 r 18 20\r\nNATS/1.0\r\nA: 1\r\n\r\nhi\r\n";
+        assert_eq!(read_until(&mut with_headers, expect).await, expect);
+        let expect = b"MSG foo 2 r 2\r\nhi\r\n";
+        assert_eq!(read_until(&mut without_headers, expect).await, expect);
+
+        // Reject时不支持消息头的订阅者收不到消息
+        Arc::make_mut(&mut state.lock().await.config).header_policy = HeaderPolicy::Reject;
+        with_headers
+            .write_all(b"HPUB foo 12 13\r\nNATS/1.0\r\n\r\nx\r\nPUB foo 1\r\ny\r\n")
+            .await
+            .unwrap();
+        let expect = b"MSG foo 2 1\r\ny\r\n";
+        assert_eq!(read_until(&mut without_headers, expect).await, expect);
+
+        with_headers
+            .write_all(b"HPUB foo 3 3\r\nabc\r\n")
+            .await
+            .unwrap();
+        let expect = b"HMSG foo 1 12 13\r\nNATS/1.0\r\n\r\nx\r\nMSG foo 1 1\r\ny\r\n-ERR 'Invalid Header'\r\n";
+        assert_eq!(read_until(&mut with_headers, expect).await, expect);
+    }
+
+    #[tokio::test]
+    async fn test_queue_round_robin() {
+        let state: Arc<Mutex<ServerState<TrieSubList>>> = Default::default();
+        state.lock().await.queue_selector = QueueStrategy::RoundRobin.selector().into();
+        let mut workers = Vec::new();
+        for sid in ["1", "2"] {
+            let mut worker = connect(&state).await;
+            worker
+                .write_all(format!("SUB jobs.* workers {}\r\nPING\r\n", sid).as_bytes())
+                .await
+                .unwrap();
+            assert_eq!(read_until(&mut worker, b"PONG\r\n").await, b"PONG\r\n");
+            workers.push(worker);
+        }
+        let mut publisher = connect(&state).await;
+        publisher
+            .write_all(b"PUB jobs.a 1\r\na\r\nPUB jobs.b 1\r\nb\r\nPUB jobs.c 1\r\nc\r\nPUB jobs.d 1\r\nd\r\n")
+            .await
+            .unwrap();
+        // 每个任务只投递一次,两个worker各收到两个
+        let mut all = String::new();
+        for worker in workers.iter_mut() {
+            let data = read_until(worker, b"MSG jobs.a 1 1\r\na\r\nMSG jobs.b 1 1\r\nb\r\n").await;
+            all.push_str(&String::from_utf8(data).unwrap());
+        }
+        for job in ["jobs.a", "jobs.b", "jobs.c", "jobs.d"] {
+            assert_eq!(all.matches(job).count(), 1, "{}", all);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_max_subscriptions() {
+        let state: Arc<Mutex<ServerState<TrieSubList>>> = Default::default();
+        state.lock().await.config = Arc::new(ServerConfig {
+            max_subscriptions: 2,
+            ..Default::default()
+        });
+        let mut conn = connect(&state).await;
+        conn.write_all(b"SUB a 1\r\nSUB b 2\r\nSUB c 3\r\nSUB c 2\r\nPING\r\n")
+            .await
+            .unwrap();
+        let expect = b"-ERR 'maximum subscriptions exceeded'\r\nPONG\r\n";
+        assert_eq!(read_until(&mut conn, expect).await, expect);
+        let s = state.lock().await;
+        assert_eq!(s.stats.max_subscriptions_exceeded, 1);
+        assert_eq!(s.accounts[GLOBAL_ACCOUNT].sub_list.count(), 2);
+        assert!(s.accounts[GLOBAL_ACCOUNT]
+            .sub_list
+            .match_subject("b")
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_account_max_subscriptions() {
+        let state: Arc<Mutex<ServerState<SimpleSubList>>> = Default::default();
+        let config: ServerConfig = serde_json::from_str(
+            r#"{"accounts":{"A":{"users":[{"user":"a","pass":"a"}],"max_subscriptions":3}}}"#,
+        )
+        .unwrap();
+        state.lock().await.config = Arc::new(config);
+        let expects: [&[u8]; 2] = [
+            b"PONG\r\n",
+            b"-ERR 'maximum subscriptions exceeded'\r\nPONG\r\n",
+        ];
+        // 保持连接,断开时订阅会被删除
+        let mut conns = Vec::new();
+        for expect in expects {
+            let mut conn = connect(&state).await;
+            conn.write_all(
+                b"CONNECT {\"user\":\"a\",\"pass\":\"a\"}\r\nSUB a 1\r\nSUB b 2\r\nPING\r\n",
+            )
+            .await
+            .unwrap();
+            assert_eq!(read_until(&mut conn, expect).await, expect);
+            conns.push(conn);
+        }
+        let s = state.lock().await;
+        assert_eq!(s.stats.account_max_subscriptions_exceeded, 1);
+        assert_eq!(s.stats.max_subscriptions_exceeded, 0);
+        assert_eq!(s.accounts["A"].sub_list.count(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_auth() {
+        let state: Arc<Mutex<ServerState<TrieSubList>>> = Default::default();
+        state.lock().await.config = Arc::new(ServerConfig {
+            authorization: AuthConfig {
+                user: Some("admin".to_string()),
+                password: Some("secret".to_string()),
+                timeout: Duration::from_millis(100),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let cases: [(&[u8], &[u8]); 4] = [
+            (b"PING\r\n", b"-ERR 'Authorization Violation'\r\n"),
+            (
+                b"CONNECT {\"user\":\"admin\",\"pass\":\"x\"}\r\n",
+                b"-ERR 'Authorization Violation'\r\n",
+            ),
+            (b"", b"-ERR 'Authentication Timeout'\r\n"),
+            (
+                b"CONNECT {\"user\":\"admin\",\"pass\":\"secret\"}\r\nPING\r\n",
+                b"PONG\r\n",
+            ),
+        ];
+        for (input, expect) in cases {
+            let mut conn = connect(&state).await;
+            conn.write_all(input).await.unwrap();
+            assert_eq!(read_until(&mut conn, expect).await, expect);
+            if expect.starts_with(b"-ERR") {
+                let mut buf = [0u8; 16];
+                assert_eq!(conn.read(&mut buf).await.unwrap(), 0);
+            }
+        }
+        // 认证通过后不受认证超时影响
+        let mut conn = connect(&state).await;
+        conn.write_all(b"CONNECT {\"user\":\"admin\",\"pass\":\"secret\"}\r\n")
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        conn.write_all(b"PING\r\n").await.unwrap();
+        assert_eq!(read_until(&mut conn, b"PONG\r\n").await, b"PONG\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_permissions() {
+        let state: Arc<Mutex<ServerState<TrieSubList>>> = Default::default();
+        let authorization: AuthConfig = serde_json::from_str(
+            r#"{"users":[{"user":"a","pass":"a","permissions":{
+                "publish":"foo.>","subscribe":{"allow":"foo.*","deny":"foo.secret"}}}]}"#,
+        )
+        .unwrap();
+        state.lock().await.config = Arc::new(ServerConfig {
+            authorization,
+            ..Default::default()
+        });
+        let mut conn = connect(&state).await;
+        conn.write_all(b"CONNECT {\"user\":\"a\",\"pass\":\"a\"}\r\nSUB foo.* 1\r\nSUB bar 2\r\n")
+            .await
+            .unwrap();
+        conn.write_all(b"PUB foo.secret 1\r\ns\r\nPUB bar 1\r\nb\r\nPUB foo.a 1\r\na\r\n")
+            .await
+            .unwrap();
+        let expect = b"-ERR 'Permissions Violation for Subscription to bar'\r\n\
+            -ERR 'Permissions Violation for Publish to bar'\r\nMSG foo.a 1 1\r\na\r\n";
+        assert_eq!(read_until(&mut conn, expect).await, expect);
+    }
+
+    #[tokio::test]
+    async fn test_accounts() {
+        let state: Arc<Mutex<ServerState<TrieSubList>>> = Default::default();
+        let config: ServerConfig = serde_json::from_str(
+            r#"{"accounts":{"A":{"users":[{"user":"a","pass":"a"}]},"B":{"users":[{"user":"b","pass":"b"}]}}}"#,
+        )
+        .unwrap();
+        state.lock().await.config = Arc::new(config);
+        let mut a = connect(&state).await;
+        let mut b = connect(&state).await;
+        for (conn, user) in [(&mut a, "a"), (&mut b, "b")] {
+            let connect = format!(
+                "CONNECT {{\"user\":\"{0}\",\"pass\":\"{0}\"}}\r\nSUB orders.> 1\r\nPING\r\n",
+                user
+            );
+            conn.write_all(connect.as_bytes()).await.unwrap();
+            assert_eq!(read_until(conn, b"PONG\r\n").await, b"PONG\r\n");
+        }
+
+        a.write_all(b"PUB orders.1 1\r\na\r\nPING\r\n")
+            .await
+            .unwrap();
+        let expect = b"MSG orders.1 1 1\r\na\r\nPONG\r\n";
+        assert_eq!(read_until(&mut a, expect).await, expect);
+        // 其他账户的订阅者收不到
+        b.write_all(b"PING\r\n").await.unwrap();
+        assert_eq!(read_until(&mut b, b"PONG\r\n").await, b"PONG\r\n");
+
+        let s = state.lock().await;
+        for name in ["A", "B"] {
+            assert_eq!(s.accounts[name].clients.len(), 1);
+            assert_eq!(s.accounts[name].sub_list.count(), 1);
+        }
+        assert_eq!(s.accounts[GLOBAL_ACCOUNT].sub_list.count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_exports_imports() {
+        let state: Arc<Mutex<ServerState<TrieSubList>>> = Default::default();
+        let config: ServerConfig = serde_json::from_str(
+            r#"{"accounts":{
+                "A":{"users":[{"user":"a","pass":"a"}],
+                     "exports":[{"stream":"events.>"},{"service":"api.billing.*","accounts":["B"]}]},
+                "B":{"users":[{"user":"b","pass":"b"}],
+                     "imports":[{"stream":{"account":"A","subject":"events.>"},"prefix":"a"},
+                                {"service":{"account":"A","subject":"api.billing.*"},"to":"billing.*"}]}
+            }}"#,
+        )
+        .unwrap();
+        state.lock().await.config = Arc::new(config);
+        let mut a = connect(&state).await;
+        let mut b = connect(&state).await;
+        a.write_all(b"CONNECT {\"user\":\"a\",\"pass\":\"a\"}\r\nSUB api.billing.* 1\r\nPING\r\n")
+            .await
+            .unwrap();
+        assert_eq!(read_until(&mut a, b"PONG\r\n").await, b"PONG\r\n");
+        b.write_all(
+            b"CONNECT {\"user\":\"b\",\"pass\":\"b\"}\r\nSUB a.events.> 1\r\nSUB _INBOX.1 2\r\nPING\r\n",
+        )
+        .await
+        .unwrap();
+        assert_eq!(read_until(&mut b, b"PONG\r\n").await, b"PONG\r\n");
+
+        // stream: A发布的消息在B中加上前缀
+        a.write_all(b"PUB events.x 1\r\ne\r\n").await.unwrap();
+        let expect = b"MSG a.events.x 1 1\r\ne\r\n";
+        assert_eq!(read_until(&mut b, expect).await, expect);
+
+        // service: B的请求映射到A的主题,reply_to被替换
+        b.write_all(b"PUB billing.get _INBOX.1 3\r\nget\r\n")
+            .await
+            .unwrap();
+        let prefix = format!("MSG api.billing.get 1 {}", RESPONSE_PREFIX);
+        let len = prefix.len() + RESPONSE_ID_LEN + " 3\r\nget\r\n".len();
+        let received = read_until(&mut a, &vec![0; len]).await;
+        let line = String::from_utf8(received).unwrap();
+        assert!(line.starts_with(&prefix), "{}", line);
+        let reply_to = line.split(' ').nth(3).unwrap();
+        a.write_all(format!("PUB {} 2\r\nok\r\n", reply_to).as_bytes())
+            .await
+            .unwrap();
+        let expect = b"MSG _INBOX.1 2 2\r\nok\r\n";
+        assert_eq!(read_until(&mut b, expect).await, expect);
+        // 响应映射只使用一次
+        assert!(state.lock().await.accounts["A"].responses.is_empty());
+
+        // 导出服务没有订阅者时不记录响应映射
+        a.write_all(b"UNSUB 1\r\nPING\r\n").await.unwrap();
+        assert_eq!(read_until(&mut a, b"PONG\r\n").await, b"PONG\r\n");
+        b.write_all(b"PUB billing.get _INBOX.1 3\r\nget\r\nPING\r\n")
+            .await
+            .unwrap();
+        assert_eq!(read_until(&mut b, b"PONG\r\n").await, b"PONG\r\n");
+        assert!(state.lock().await.accounts["A"].responses.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_slow_consumer() {
+        // 对端一直不读取,写满缓冲区后超时
+        let (writer, _reader) = tokio::io::duplex(16);
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut sender = ClientMessageSender::new(writer);
+        sender.event_tx = Some(tx);
+        sender.write_deadline = Some(Duration::from_millis(50));
+        let err = sender.send_raw(&[b'x'; 64]).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        assert!(matches!(rx.recv().await, Some(ClientEvent::SlowConsumer)));
+        // 写端已经丢弃,之后的推送都被忽略
+        assert!(sender.send_raw(b"PING\r\n").await.is_ok());
     }
 }
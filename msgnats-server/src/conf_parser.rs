@@ -0,0 +1,539 @@
+use serde_json::{Map, Number, Value};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+
+/**
+NATS服务端配置文件格式的解析,结果转换成serde_json::Value,再反序列化成配置结构体.
+```text
+# 注释, // 也可以
+port: 4222
+host = 0.0.0.0
+max_payload 1MB
+authorization {
+    user: admin
+    password: $ADMIN_PASS
+}
+include ./accounts.conf
+```
+- key和value之间可以用`:`、`=`或者空白分隔,`{`开始的块可以省略分隔符
+- 值可以是带引号或不带引号的字符串、整数(支持K/KB/M/MB/G/GB后缀)、浮点数、布尔值、数组`[...]`以及块`{...}`
+- `$name`引用当前块或者外层块中已经定义的key,找不到时使用同名环境变量
+- `include <path>`把另一个文件的内容合并到当前块,相对路径以当前文件所在目录为准
+*/
+const MAX_INCLUDE_DEPTH: usize = 10;
+
+/**
+ * 配置错误,包含出错的文件以及行列位置,行列从1开始,为0表示没有具体位置
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfError {
+    pub file: PathBuf,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl ConfError {
+    pub fn new(file: &Path, line: usize, column: usize, message: String) -> Self {
+        Self {
+            file: file.to_path_buf(),
+            line,
+            column,
+            message,
+        }
+    }
+}
+
+impl Display for ConfError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.line == 0 {
+            write!(f, "{}: {}", self.file.display(), self.message)
+        } else {
+            write!(
+                f,
+                "{}:{}:{}: {}",
+                self.file.display(),
+                self.line,
+                self.column,
+                self.message
+            )
+        }
+    }
+}
+
+impl Error for ConfError {}
+
+pub type ConfResult<T> = std::result::Result<T, ConfError>;
+
+// 配置项在文件中的位置
+#[derive(Debug, Clone, PartialEq)]
+pub struct Position {
+    pub file: PathBuf,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Position {
+    pub fn error(&self, message: String) -> ConfError {
+        ConfError::new(&self.file, self.line, self.column, message)
+    }
+}
+
+/**
+ * 解析结果,keys记录了顶层配置项最后一次出现的位置,用于报告配置项的值不合法
+ */
+#[derive(Debug, Default)]
+pub struct Conf {
+    pub values: Map<String, Value>,
+    pub keys: HashMap<String, Position>,
+}
+
+pub fn parse_file(path: &Path) -> ConfResult<Conf> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| ConfError::new(path, 0, 0, format!("read config file error:{}", e)))?;
+    parse_str(path, &text)
+}
+
+// path只用于报告错误位置以及解析include的相对路径
+pub fn parse_str(path: &Path, text: &str) -> ConfResult<Conf> {
+    let mut parser = ConfParser::new(path, text, 0);
+    parser.scopes.push(Map::new());
+    parser.parse_entries(None)?;
+    Ok(Conf {
+        values: parser.scopes.pop().unwrap_or_default(),
+        keys: parser.keys,
+    })
+}
+
+struct ConfParser {
+    file: PathBuf,
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+    column: usize,
+    depth: usize,                    // include的嵌套层数,防止循环include
+    scopes: Vec<Map<String, Value>>, // 正在解析的块,最后一个是当前块
+    keys: HashMap<String, Position>,
+}
+
+impl ConfParser {
+    fn new(file: &Path, text: &str, depth: usize) -> Self {
+        Self {
+            file: file.to_path_buf(),
+            chars: text.chars().collect(),
+            pos: 0,
+            line: 1,
+            column: 1,
+            depth,
+            scopes: Vec::new(),
+            keys: HashMap::new(),
+        }
+    }
+
+    fn position(&self) -> Position {
+        Position {
+            file: self.file.clone(),
+            line: self.line,
+            column: self.column,
+        }
+    }
+
+    fn error<T>(&self, message: String) -> ConfResult<T> {
+        Err(self.position().error(message))
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn peek_next(&self) -> Option<char> {
+        self.chars.get(self.pos + 1).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    fn at_comment(&self) -> bool {
+        self.peek() == Some('#') || (self.peek() == Some('/') && self.peek_next() == Some('/'))
+    }
+
+    fn skip_line(&mut self) {
+        while !matches!(self.peek(), None | Some('\n')) {
+            self.next();
+        }
+    }
+
+    // 跳过同一行内的空白和注释
+    fn skip_spaces(&mut self) {
+        loop {
+            match self.peek() {
+                Some(' ' | '\t' | '\r') => {
+                    self.next();
+                }
+                _ if self.at_comment() => self.skip_line(),
+                _ => return,
+            }
+        }
+    }
+
+    // 跳过空白、注释、换行以及配置项之间的分隔符
+    fn skip_separators(&mut self) {
+        loop {
+            self.skip_spaces();
+            match self.peek() {
+                Some('\n' | ',' | ';') => {
+                    self.next();
+                }
+                _ => return,
+            }
+        }
+    }
+
+    /**
+     * 解析key value直到文件结束或者遇到块的结束符close
+     */
+    fn parse_entries(&mut self, close: Option<char>) -> ConfResult<()> {
+        loop {
+            self.skip_separators();
+            match self.peek() {
+                None if close.is_none() => return Ok(()),
+                None => return self.error(format!("expected '{}'", close.unwrap_or_default())),
+                Some(c) if Some(c) == close => {
+                    self.next();
+                    return Ok(());
+                }
+                Some(_) => self.parse_entry()?,
+            }
+        }
+    }
+
+    fn parse_entry(&mut self) -> ConfResult<()> {
+        let position = self.position();
+        let key = match self.peek() {
+            Some(quote @ ('"' | '\'')) => self.parse_quoted(quote)?,
+            _ => self.take_while(|c| !c.is_whitespace() && !":={}[],;#".contains(c)),
+        };
+        if key.is_empty() {
+            return self.error(format!("unexpected '{}'", self.peek().unwrap_or_default()));
+        }
+        self.skip_spaces();
+        if key == "include" && !matches!(self.peek(), Some(':' | '=' | '{' | '[')) {
+            return self.parse_include(&position);
+        }
+        if matches!(self.peek(), Some(':' | '=')) {
+            self.next();
+            self.skip_spaces();
+        }
+        let value = self.parse_value()?;
+        self.skip_spaces();
+        if !matches!(self.peek(), None | Some('\n' | ',' | ';' | '}')) {
+            return self.error(format!(
+                "unexpected '{}' after value of {}",
+                self.peek().unwrap_or_default(),
+                key
+            ));
+        }
+        if self.scopes.len() == 1 {
+            self.keys.insert(key.clone(), position);
+        }
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(key, value);
+        }
+        Ok(())
+    }
+
+    fn parse_value(&mut self) -> ConfResult<Value> {
+        match self.peek() {
+            None | Some('\n') => self.error("missing value".to_string()),
+            Some('{') => {
+                self.next();
+                self.scopes.push(Map::new());
+                let r = self.parse_entries(Some('}'));
+                let map = self.scopes.pop().unwrap_or_default();
+                r.map(|_| Value::Object(map))
+            }
+            Some('[') => self.parse_array(),
+            Some(quote @ ('"' | '\'')) => self.parse_quoted(quote).map(Value::String),
+            Some('$') => self.parse_variable(),
+            Some(_) => {
+                let position = self.position();
+                let token = self.parse_bare()?;
+                if token.is_empty() {
+                    return Err(position.error("missing value".to_string()));
+                }
+                parse_bare_value(&token).map_err(|e| position.error(e))
+            }
+        }
+    }
+
+    fn parse_array(&mut self) -> ConfResult<Value> {
+        self.next();
+        let mut values = Vec::new();
+        loop {
+            self.skip_separators();
+            if self.peek() == Some(']') {
+                self.next();
+                return Ok(Value::Array(values));
+            }
+            if self.peek().is_none() {
+                return self.error("expected ']'".to_string());
+            }
+            values.push(self.parse_value()?);
+        }
+    }
+
+    fn parse_quoted(&mut self, quote: char) -> ConfResult<String> {
+        let position = self.position();
+        self.next();
+        let mut s = String::new();
+        loop {
+            match self.next() {
+                None | Some('\n') => return Err(position.error("unterminated string".to_string())),
+                Some(c) if c == quote => return Ok(s),
+                // 单引号中的内容不转义
+                Some('\\') if quote == '"' => {
+                    let c = match self.next() {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some(c @ ('"' | '\\' | '$')) => c,
+                        c => {
+                            return self
+                                .error(format!("invalid escape '\\{}'", c.unwrap_or_default()))
+                        }
+                    };
+                    s.push(c);
+                }
+                Some(c) => s.push(c),
+            }
+        }
+    }
+
+    // 不带引号的值一直到行尾或者分隔符,前面有空白的#和//是注释
+    fn parse_bare(&mut self) -> ConfResult<String> {
+        let mut s = String::new();
+        while let Some(c) = self.peek() {
+            if matches!(c, '\n' | ',' | ';' | '}' | ']') {
+                break;
+            }
+            if self.at_comment() && s.ends_with(char::is_whitespace) {
+                break;
+            }
+            s.push(c);
+            self.next();
+        }
+        Ok(s.trim_end().to_string())
+    }
+
+    // $name,先在当前块和外层块中查找,找不到再查找环境变量
+    fn parse_variable(&mut self) -> ConfResult<Value> {
+        let position = self.position();
+        self.next();
+        let name = self.take_while(|c| c.is_ascii_alphanumeric() || c == '_');
+        if name.is_empty() {
+            return Err(position.error("missing variable name after '$'".to_string()));
+        }
+        if let Some(value) = self.scopes.iter().rev().find_map(|scope| scope.get(&name)) {
+            return Ok(value.clone());
+        }
+        match std::env::var(&name) {
+            Ok(value) => parse_bare_value(&value).map_err(|e| position.error(e)),
+            Err(_) => Err(position.error(format!("variable reference '{}' not found", name))),
+        }
+    }
+
+    // include的内容合并到当前块,变量可以引用include之前定义的key
+    fn parse_include(&mut self, position: &Position) -> ConfResult<()> {
+        let path = match self.peek() {
+            Some(quote @ ('"' | '\'')) => self.parse_quoted(quote)?,
+            _ => self.parse_bare()?,
+        };
+        if path.is_empty() {
+            return Err(position.error("missing include path".to_string()));
+        }
+        if self.depth >= MAX_INCLUDE_DEPTH {
+            return Err(position.error(format!("include {} nested too deep", path)));
+        }
+        let path = match self.file.parent() {
+            Some(dir) => dir.join(path),
+            None => PathBuf::from(path),
+        };
+        let text = std::fs::read_to_string(&path)
+            .map_err(|e| position.error(format!("include {} error:{}", path.display(), e)))?;
+        let mut parser = ConfParser::new(&path, &text, self.depth + 1);
+        parser.scopes = std::mem::take(&mut self.scopes);
+        parser.keys = std::mem::take(&mut self.keys);
+        let r = parser.parse_entries(None);
+        self.scopes = parser.scopes;
+        self.keys = parser.keys;
+        r
+    }
+
+    fn take_while<F: Fn(char) -> bool>(&mut self, f: F) -> String {
+        let mut s = String::new();
+        while let Some(c) = self.peek().filter(|c| f(*c)) {
+            s.push(c);
+            self.next();
+        }
+        s
+    }
+}
+
+/**
+ * 解析大小,可以带K/KB/M/MB/G/GB后缀,比如命令行参数中的1MB
+ */
+pub fn parse_size(s: &str) -> Option<usize> {
+    match parse_bare_value(s.trim()) {
+        Ok(Value::Number(n)) => n.as_u64().and_then(|n| usize::try_from(n).ok()),
+        _ => None,
+    }
+}
+
+/**
+ * 不带引号的值:布尔值、数字、带单位的大小,其他的都当做字符串
+ * 像10s、0.0.0.0这样不是数字的值也是字符串
+ */
+fn parse_bare_value(s: &str) -> Result<Value, String> {
+    match s.to_ascii_lowercase().as_str() {
+        "true" | "yes" | "on" => return Ok(Value::Bool(true)),
+        "false" | "no" | "off" => return Ok(Value::Bool(false)),
+        _ => {}
+    }
+    if !s.starts_with(|c: char| c.is_ascii_digit() || c == '-') {
+        return Ok(Value::String(s.to_string()));
+    }
+    if let Ok(n) = s.parse::<i64>() {
+        return Ok(Value::from(n));
+    }
+    if s.chars().all(|c| c.is_ascii_digit() || "+-.eE".contains(c)) {
+        if let Some(n) = s.parse::<f64>().ok().and_then(Number::from_f64) {
+            return Ok(Value::Number(n));
+        }
+    }
+    let pos = s[1..]
+        .find(|c: char| !c.is_ascii_digit())
+        .map_or(s.len(), |pos| pos + 1);
+    let (num, unit) = s.split_at(pos);
+    let unit: i64 = match unit.to_ascii_lowercase().as_str() {
+        "k" => 1000,
+        "kb" | "ki" | "kib" => 1024,
+        "m" => 1000 * 1000,
+        "mb" | "mi" | "mib" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" | "gi" | "gib" => 1024 * 1024 * 1024,
+        _ => return Ok(Value::String(s.to_string())),
+    };
+    num.parse::<i64>()
+        .ok()
+        .and_then(|n| n.checked_mul(unit))
+        .map(Value::from)
+        .ok_or_else(|| format!("invalid size {}", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn parse(text: &str) -> ConfResult<Value> {
+        parse_str(Path::new("test.conf"), text).map(|conf| Value::Object(conf.values))
+    }
+
+    #[test]
+    fn test_values() {
+        let v = parse(
+            "# comment\nport: 4222\nhost = 0.0.0.0 // comment\nmax_payload 1MB\n\
+             ping_interval: \"2m\"\ndebug: true; trace: off\nratio: 0.5\n\
+             name: 'a\\b'\nurls: [nats://a:4222, \"b\"\n  c]\nlimit: 2k",
+        )
+        .unwrap();
+        assert_eq!(
+            v,
+            json!({
+                "port": 4222,
+                "host": "0.0.0.0",
+                "max_payload": 1048576,
+                "ping_interval": "2m",
+                "debug": true,
+                "trace": false,
+                "ratio": 0.5,
+                "name": "a\\b",
+                "urls": ["nats://a:4222", "b", "c"],
+                "limit": 2000,
+            })
+        );
+    }
+
+    #[test]
+    fn test_blocks_and_variables() {
+        std::env::set_var("MSGNATS_TEST_PASS", "s3cret");
+        let v = parse(
+            "user = admin\nauthorization {\n  user: $user\n  password: $MSGNATS_TEST_PASS\n  \
+             timeout 2\n  users = [{name: a, pass: $user}]\n}\n",
+        )
+        .unwrap();
+        assert_eq!(
+            v["authorization"],
+            json!({
+                "user": "admin",
+                "password": "s3cret",
+                "timeout": 2,
+                "users": [{"name": "a", "pass": "admin"}],
+            })
+        );
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = std::env::temp_dir().join(format!("msgnats-conf-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("auth.conf"), "user: a\npass: $secret\n").unwrap();
+        std::fs::write(
+            dir.join("main.conf"),
+            "secret: x\nauthorization {\n  include ./auth.conf\n}\nport: 1\ninclude 'loop.conf'\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("loop.conf"), "include loop.conf\n").unwrap();
+        let err = parse_file(&dir.join("main.conf")).unwrap_err();
+        assert!(err.message.contains("nested too deep"), "{}", err);
+
+        std::fs::write(dir.join("loop.conf"), "port: 2\n").unwrap();
+        let conf = parse_file(&dir.join("main.conf")).unwrap();
+        assert_eq!(
+            conf.values["authorization"],
+            json!({"user": "a", "pass": "x"})
+        );
+        assert_eq!(conf.values["port"], json!(2));
+        assert_eq!(conf.keys["port"].file, dir.join("loop.conf"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_errors() {
+        let err = parse("port: 1\nhost: $missing\n").unwrap_err();
+        assert_eq!((err.line, err.column), (2, 7));
+        assert_eq!(
+            err.to_string(),
+            "test.conf:2:7: variable reference 'missing' not found"
+        );
+        let err = parse("a {\n  b: 1\n").unwrap_err();
+        assert_eq!((err.line, err.column), (3, 1));
+        let err = parse("a: \"abc\n").unwrap_err();
+        assert_eq!((err.line, err.column), (1, 4));
+        let err = parse("a: [1, 2\n").unwrap_err();
+        assert!(err.message.contains("']'"));
+        let err = parse("a: {b: 1} c\n").unwrap_err();
+        assert_eq!((err.line, err.column), (1, 11));
+    }
+}
@@ -0,0 +1,375 @@
+use crate::account::{AccountConfig, GLOBAL_ACCOUNT};
+use crate::auth::{find_user, find_user_by_identity, AuthConfig, Permissions, User};
+use crate::conf_parser::{self, ConfError, ConfResult};
+use crate::gateway::GatewayConfig;
+use crate::headers::HeaderPolicy;
+use crate::info::ConnectOptions;
+use crate::jetstream::JetStreamConfig;
+use crate::leaf::LeafNodeConfig;
+use crate::queue::QueueStrategy;
+use crate::route::ClusterConfig;
+use crate::tls::TlsConfig;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/**
+服务端配置,不同的部署可以使用不同的监听地址和各种限制.
+数量相关的限制为0时表示不限制.
+*/
+pub const DEFAULT_HOST: &str = "127.0.0.1";
+pub const DEFAULT_PORT: u16 = 18888;
+// 消息体长度不应该超过1M,防止Dos攻击
+pub const DEFAULT_MAX_PAYLOAD: usize = 1024 * 1024;
+// 协议控制行(不包括消息体)的最大长度,必须能放下完整的主题以及参数
+pub const DEFAULT_MAX_CONTROL_LINE: usize = 4096;
+pub const DEFAULT_MAX_CONNECTIONS: usize = 64 * 1024;
+// 服务端主动PING的默认间隔
+pub const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(120);
+// 默认允许未回复PONG的PING数量,超过后认为连接已经失效
+pub const DEFAULT_MAX_PINGS_OUT: usize = 2;
+// 向客户端写数据的超时时间,超时认为是慢消费者
+pub const DEFAULT_WRITE_DEADLINE: Duration = Duration::from_secs(10);
+pub const DEFAULT_LOG_LEVEL: &str = "info";
+// lame duck mode下逐步关闭所有客户端连接的时间
+pub const DEFAULT_LAME_DUCK_DURATION: Duration = Duration::from_secs(120);
+// lame duck mode开始后,等待这段时间再开始关闭客户端连接
+pub const DEFAULT_LAME_DUCK_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ServerConfig {
+    #[serde(alias = "net")]
+    pub host: String,
+    pub port: u16,
+    pub max_payload: usize,
+    pub max_control_line: usize,
+    pub max_connections: usize,
+    pub max_subscriptions: usize, // 每个客户端的最大订阅数量
+    #[serde(with = "duration_format")]
+    pub ping_interval: Duration,
+    #[serde(alias = "ping_max")]
+    pub max_pings_out: usize,
+    #[serde(with = "duration_format")]
+    pub write_deadline: Duration,
+    #[serde(with = "duration_format")]
+    pub lame_duck_duration: Duration,
+    #[serde(with = "duration_format")]
+    pub lame_duck_grace_period: Duration,
+    pub header_policy: HeaderPolicy,
+    pub queue_strategy: QueueStrategy,
+    pub authorization: AuthConfig,
+    pub accounts: BTreeMap<String, AccountConfig>,
+    pub tls: Option<TlsConfig>, // 配置后客户端必须使用TLS连接
+    pub cluster: Option<ClusterConfig>,
+    pub leafnodes: Option<LeafNodeConfig>,
+    pub gateway: Option<GatewayConfig>,
+    pub jetstream: Option<JetStreamConfig>, // 配置后开启stream的持久化
+    pub log_level: String,                  // env_logger的过滤规则,比如info或者msgnats_server=debug
+    pub pid_file: Option<PathBuf>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            host: DEFAULT_HOST.to_string(),
+            port: DEFAULT_PORT,
+            max_payload: DEFAULT_MAX_PAYLOAD,
+            max_control_line: DEFAULT_MAX_CONTROL_LINE,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_subscriptions: 0,
+            ping_interval: DEFAULT_PING_INTERVAL,
+            max_pings_out: DEFAULT_MAX_PINGS_OUT,
+            write_deadline: DEFAULT_WRITE_DEADLINE,
+            lame_duck_duration: DEFAULT_LAME_DUCK_DURATION,
+            lame_duck_grace_period: DEFAULT_LAME_DUCK_GRACE_PERIOD,
+            header_policy: HeaderPolicy::default(),
+            queue_strategy: QueueStrategy::default(),
+            authorization: AuthConfig::default(),
+            accounts: BTreeMap::new(),
+            tls: None,
+            cluster: None,
+            leafnodes: None,
+            gateway: None,
+            jetstream: None,
+            log_level: DEFAULT_LOG_LEVEL.to_string(),
+            pid_file: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn addr(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+
+    // authorization或者任意一个账户配置了用户时,客户端都需要认证
+    pub fn auth_required(&self) -> bool {
+        self.authorization.is_required()
+            || self.accounts.values().any(|a| !a.users.is_empty())
+            || self.verify_and_map()
+    }
+
+    // 使用客户端证书中的身份认证
+    pub fn verify_and_map(&self) -> bool {
+        self.tls.as_ref().is_some_and(|tls| tls.verify_and_map)
+    }
+
+    /**
+     * 认证通过时返回客户端所属的账户以及权限
+     * 账户中的用户没有配置权限时使用authorization中的default_permissions
+     */
+    pub fn authenticate(&self, opts: &ConnectOptions) -> Option<(&str, &Permissions)> {
+        if self.authorization.is_required() || !self.auth_required() {
+            if let Some(permissions) = self.authorization.authenticate(opts) {
+                return Some((GLOBAL_ACCOUNT, permissions));
+            }
+        }
+        self.accounts.iter().find_map(|(name, account)| {
+            find_user(&account.users, opts).map(|u| (name.as_str(), self.user_permissions(u)))
+        })
+    }
+
+    /**
+     * 按照客户端证书中的身份查找用户,用户名和任意一个身份相同即可,不检查密码
+     */
+    pub fn authenticate_identities(&self, identities: &[String]) -> Option<(&str, &Permissions)> {
+        if let Some(u) = find_user_by_identity(&self.authorization.users, identities) {
+            return Some((GLOBAL_ACCOUNT, self.user_permissions(u)));
+        }
+        self.accounts.iter().find_map(|(name, account)| {
+            find_user_by_identity(&account.users, identities)
+                .map(|u| (name.as_str(), self.user_permissions(u)))
+        })
+    }
+
+    // 用户没有配置权限时使用default_permissions
+    fn user_permissions<'a>(&'a self, user: &'a User) -> &'a Permissions {
+        let permissions = user.permissions.as_ref();
+        permissions.unwrap_or(&self.authorization.default_permissions)
+    }
+
+    /**
+     * 从配置文件加载,参见conf_parser中的格式说明
+     */
+    pub fn from_file<P: AsRef<Path>>(path: P) -> ConfResult<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| ConfError::new(path, 0, 0, format!("read config file error:{}", e)))?;
+        Self::from_conf(path, &text)
+    }
+
+    // 内容以{开始时按照JSON解析,否则按照NATS配置文件格式解析
+    pub fn from_conf(path: &Path, text: &str) -> ConfResult<Self> {
+        if text.trim_start().starts_with('{') {
+            let config: Self = serde_json::from_str(text).map_err(|e| {
+                // serde_json的错误信息中已经带有位置,去掉避免重复
+                let message = e.to_string();
+                let message = message.split(" at line ").next().unwrap_or_default();
+                ConfError::new(path, e.line(), e.column(), message.to_string())
+            })?;
+            return match config.validate() {
+                Ok(()) => Ok(config),
+                Err((_, message)) => Err(ConfError::new(path, 0, 0, message)),
+            };
+        }
+        let conf = conf_parser::parse_str(path, text)?;
+        // 逐个配置项检查,出错时可以定位到配置项所在的位置
+        for (key, value) in conf.values.iter() {
+            let mut one = Map::new();
+            one.insert(key.clone(), value.clone());
+            if let Err(e) = serde_json::from_value::<ServerConfig>(Value::Object(one)) {
+                let message = format!("invalid {}: {}", key, e);
+                return Err(match conf.keys.get(key) {
+                    Some(position) => position.error(message),
+                    None => ConfError::new(path, 0, 0, message),
+                });
+            }
+        }
+        let config: Self = serde_json::from_value(Value::Object(conf.values))
+            .map_err(|e| ConfError::new(path, 0, 0, e.to_string()))?;
+        match config.validate() {
+            Ok(()) => Ok(config),
+            Err((key, message)) => Err(match conf.keys.get(key) {
+                Some(position) => position.error(message),
+                None => ConfError::new(path, 0, 0, message),
+            }),
+        }
+    }
+
+    /**
+     * 检查不能为0的配置项,返回出错的配置项以及原因
+     * ping_interval为0时定时器无法创建,max_control_line为0时任何协议都无法解析,
+     * write_deadline为0时所有写操作都会立即超时
+     */
+    pub fn validate(&self) -> Result<(), (&'static str, String)> {
+        let zero = [
+            ("ping_interval", self.ping_interval.is_zero()),
+            ("max_control_line", self.max_control_line == 0),
+            ("write_deadline", self.write_deadline.is_zero()),
+        ];
+        match zero.into_iter().find(|(_, is_zero)| *is_zero) {
+            Some((key, _)) => Err((key, format!("invalid {}: must be greater than 0", key))),
+            None => Ok(()),
+        }
+    }
+}
+
+/**
+ * 解析时间间隔,支持ms/s/m/h单位,没有单位时按照秒处理
+ * 例如 500ms 30s 2m 1h 10
+ */
+pub fn parse_duration(s: &str) -> Option<Duration> {
+    let s = s.trim();
+    let pos = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (num, unit) = s.split_at(pos);
+    let num: f64 = num.parse().ok()?;
+    let secs = match unit.trim() {
+        "ms" => num / 1000.0,
+        "" | "s" => num,
+        "m" => num * 60.0,
+        "h" => num * 3600.0,
+        _ => return None,
+    };
+    if secs.is_finite() && secs >= 0.0 {
+        Some(Duration::from_secs_f64(secs))
+    } else {
+        None
+    }
+}
+
+// 配置中的时间间隔可以是秒数,也可以是带单位的字符串
+pub(crate) mod duration_format {
+    use super::parse_duration;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    #[derive(serde_derive::Deserialize)]
+    #[serde(untagged)]
+    enum Value {
+        Secs(f64),
+        Text(String),
+    }
+
+    pub fn serialize<S: Serializer>(d: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("{}ms", d.as_millis()))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        let text = match Value::deserialize(deserializer)? {
+            Value::Secs(secs) => secs.to_string(),
+            Value::Text(text) => text,
+        };
+        parse_duration(&text).ok_or_else(|| D::Error::custom(format!("invalid duration {}", text)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("500ms"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_duration("30s"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_duration("2m"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_duration("1h"), Some(Duration::from_secs(3600)));
+        assert_eq!(parse_duration("10"), Some(Duration::from_secs(10)));
+        assert_eq!(parse_duration("1.5s"), Some(Duration::from_millis(1500)));
+        assert_eq!(parse_duration("abc"), None);
+        assert_eq!(parse_duration("5d"), None);
+    }
+
+    #[test]
+    fn test_serde() {
+        let config: ServerConfig = serde_json::from_str(
+            r#"{"port":4222,"ping_interval":"30s","write_deadline":2,"header_policy":"reject"}"#,
+        )
+        .unwrap();
+        assert_eq!(config.port, 4222);
+        assert_eq!(config.host, DEFAULT_HOST);
+        assert_eq!(config.ping_interval, Duration::from_secs(30));
+        assert_eq!(config.write_deadline, Duration::from_secs(2));
+        assert_eq!(config.header_policy, HeaderPolicy::Reject);
+        assert_eq!(config.addr(), "127.0.0.1:4222");
+
+        let s = serde_json::to_string(&config).unwrap();
+        assert_eq!(serde_json::from_str::<ServerConfig>(&s).unwrap(), config);
+    }
+
+    #[test]
+    fn test_from_conf() {
+        let path = Path::new("server.conf");
+        let config = ServerConfig::from_conf(
+            path,
+            "net: 0.0.0.0\nport: 4222\nmax_payload: 2MB\nping_interval: 30s\nping_max: 3\n\
+             queue_strategy: round_robin\nauthorization {\n  user: admin\n}\n",
+        )
+        .unwrap();
+        assert_eq!(config.addr(), "0.0.0.0:4222");
+        assert_eq!(config.max_payload, 2 * 1024 * 1024);
+        assert_eq!(config.ping_interval, Duration::from_secs(30));
+        assert_eq!(config.max_pings_out, 3);
+        assert_eq!(config.queue_strategy, QueueStrategy::RoundRobin);
+        assert_eq!(config.authorization.user.as_deref(), Some("admin"));
+
+        let config = ServerConfig::from_conf(
+            path,
+            "accounts {\n  A { users = [{user: a, password: a}] }\n  B { users: [{user: b, pass: b}] }\n}\n",
+        )
+        .unwrap();
+        assert!(config.auth_required());
+        let opts = |user: &str, pass: &str| ConnectOptions {
+            user: Some(user.to_string()),
+            pass: Some(pass.to_string()),
+            ..Default::default()
+        };
+        assert_eq!(config.authenticate(&opts("a", "a")).unwrap().0, "A");
+        assert_eq!(config.authenticate(&opts("b", "b")).unwrap().0, "B");
+        assert!(config.authenticate(&opts("a", "b")).is_none());
+        assert!(config.authenticate(&ConnectOptions::default()).is_none());
+        let config = ServerConfig::default();
+        assert_eq!(
+            config.authenticate(&ConnectOptions::default()).unwrap().0,
+            GLOBAL_ACCOUNT
+        );
+
+        let config = ServerConfig::from_conf(path, "  {\"port\": 4223}").unwrap();
+        assert_eq!(config.port, 4223);
+        let err = ServerConfig::from_conf(path, "{\n  \"port\": \"x\"\n}").unwrap_err();
+        assert_eq!((err.line, err.column), (2, 13));
+
+        let err = ServerConfig::from_conf(path, "port: 1\n\n  host: [1]\n").unwrap_err();
+        assert_eq!((err.line, err.column), (3, 3));
+        assert!(err
+            .to_string()
+            .starts_with("server.conf:3:3: invalid host:"));
+    }
+
+    #[test]
+    fn test_validate() {
+        let path = Path::new("server.conf");
+        let err = ServerConfig::from_conf(
+            path,
+            "port: 1
+ping_interval: 0
+",
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "server.conf:2:1: invalid ping_interval: must be greater than 0"
+        );
+        let err = ServerConfig::from_conf(path, "{\"ping_interval\": 0}").unwrap_err();
+        assert!(err.to_string().contains("invalid ping_interval"));
+        let err = ServerConfig::from_conf(path, "max_control_line: 0").unwrap_err();
+        assert!(err.to_string().contains("invalid max_control_line"));
+        let err = ServerConfig::from_conf(path, "{\"write_deadline\": \"0s\"}").unwrap_err();
+        assert!(err.to_string().contains("invalid write_deadline"));
+        assert!(ServerConfig::default().validate().is_ok());
+    }
+}
@@ -9,25 +9,98 @@ pub const ERROR_MESSAGE_SIZE_TOO_LARGE: i32 = 2;
 pub const ERROR_INVALID_SUBJECT: i32 = 3;
 pub const ERROR_SUBSCRIBTION_NOT_FOUND: i32 = 4;
 pub const ERROR_CONNECTION_CLOSED: i32 = 5;
+pub const ERROR_STALE_CONNECTION: i32 = 6;
+pub const ERROR_INVALID_HEADER: i32 = 7;
+pub const ERROR_MAX_CONTROL_LINE: i32 = 8;
+pub const ERROR_MAX_CONNECTIONS: i32 = 9;
+pub const ERROR_MAX_SUBSCRIPTIONS: i32 = 10;
+pub const ERROR_SLOW_CONSUMER: i32 = 11;
+pub const ERROR_AUTHORIZATION_VIOLATION: i32 = 12;
+pub const ERROR_AUTHENTICATION_TIMEOUT: i32 = 13;
+pub const ERROR_PUBLISH_VIOLATION: i32 = 14;
+pub const ERROR_SUBSCRIPTION_VIOLATION: i32 = 15;
 
 //pub const ERROR_UNKOWN_ERROR: i32 = 1000;
 
 #[derive(Debug)]
 pub struct NError {
     err_code: i32,
+    subject: Option<String>, // 和错误相关的主题,比如没有权限发布的主题
 }
 
 impl NError {
     pub fn new(err_code: i32) -> Self {
-        NError { err_code }
+        NError {
+            err_code,
+            subject: None,
+        }
+    }
+
+    pub fn with_subject(err_code: i32, subject: &str) -> Self {
+        NError {
+            err_code,
+            subject: Some(subject.to_string()),
+        }
+    }
+
+    pub fn subject(&self) -> Option<&str> {
+        self.subject.as_deref()
+    }
+
+    pub fn err_code(&self) -> i32 {
+        self.err_code
     }
 
-    fn desc_error_message(&self) -> &'static str {
+    // 返回给客户端的错误原因,和NATS的-ERR保持一致
+    pub fn desc_error_message(&self) -> &'static str {
         match self.err_code {
-            ERROR_PARSE => "Parse error",
-            _ => "other error",
+            ERROR_MESSAGE_NONE => "Empty Message Payload",
+            ERROR_PARSE => "Unknown Protocol Operation",
+            ERROR_MESSAGE_SIZE_TOO_LARGE => "Maximum Payload Violation",
+            ERROR_INVALID_SUBJECT => "Invalid Subject",
+            ERROR_SUBSCRIBTION_NOT_FOUND => "Subscription Not Found",
+            ERROR_CONNECTION_CLOSED => "Connection Closed",
+            ERROR_STALE_CONNECTION => "Stale Connection",
+            ERROR_INVALID_HEADER => "Invalid Header",
+            ERROR_MAX_CONTROL_LINE => "Maximum Control Line Exceeded",
+            ERROR_MAX_CONNECTIONS => "maximum connections exceeded",
+            ERROR_MAX_SUBSCRIPTIONS => "maximum subscriptions exceeded",
+            ERROR_SLOW_CONSUMER => "Slow Consumer",
+            ERROR_AUTHORIZATION_VIOLATION => "Authorization Violation",
+            ERROR_AUTHENTICATION_TIMEOUT => "Authentication Timeout",
+            ERROR_PUBLISH_VIOLATION => "Permissions Violation for Publish",
+            ERROR_SUBSCRIPTION_VIOLATION => "Permissions Violation for Subscription",
+            _ => "Unknown Error",
         }
     }
+
+    /**
+     * 出错后是否需要关闭连接
+     * 协议解析相关的错误无法恢复解析状态,必须关闭;主题、订阅之类的错误只需要告知客户端
+     */
+    pub fn is_fatal(&self) -> bool {
+        !matches!(
+            self.err_code,
+            ERROR_INVALID_SUBJECT
+                | ERROR_SUBSCRIBTION_NOT_FOUND
+                | ERROR_INVALID_HEADER
+                | ERROR_MAX_SUBSCRIPTIONS
+                | ERROR_PUBLISH_VIOLATION
+                | ERROR_SUBSCRIPTION_VIOLATION
+        )
+    }
+
+    /**
+     * 协议格式 -ERR '<reason>'\r\n
+     * 有相关主题时 -ERR '<reason> to <subject>'\r\n
+     */
+    pub fn to_protocol(&self) -> Vec<u8> {
+        match self.subject {
+            Some(ref subject) => format!("-ERR '{} to {}'\r\n", self.desc_error_message(), subject),
+            None => format!("-ERR '{}'\r\n", self.desc_error_message()),
+        }
+        .into_bytes()
+    }
 }
 
 impl Error for NError {}
@@ -45,4 +118,19 @@ mod tests {
     fn test() {
         println!("{}", NError::new(ERROR_PARSE));
     }
+
+    #[test]
+    fn test_protocol() {
+        let e = NError::new(ERROR_MESSAGE_SIZE_TOO_LARGE);
+        assert_eq!(e.to_protocol(), b"-ERR 'Maximum Payload Violation'\r\n");
+        assert!(e.is_fatal());
+        let e = NError::with_subject(ERROR_PUBLISH_VIOLATION, "foo.bar");
+        assert_eq!(
+            e.to_protocol(),
+            b"-ERR 'Permissions Violation for Publish to foo.bar'\r\n"
+        );
+        assert!(!e.is_fatal());
+        assert!(!NError::new(ERROR_INVALID_SUBJECT).is_fatal());
+        assert_eq!(NError::new(1000).desc_error_message(), "Unknown Error");
+    }
 }
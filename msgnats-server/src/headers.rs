@@ -0,0 +1,188 @@
+use crate::errors::{NError, Result, ERROR_INVALID_HEADER};
+use serde_derive::{Deserialize, Serialize};
+
+/**
+HPUB/HMSG中携带的消息头,格式和HTTP头类似:
+```text
+NATS/1.0[ <status>[ <description>]]\r\n
+Key1: Value1\r\n
+Key2: Value2\r\n
+\r\n
+```
+同一个key可以出现多次,保持原有的顺序
+*/
+pub const HEADER_VERSION: &str = "NATS/1.0";
+
+/**
+ * 订阅者CONNECT时没有声明headers:true,收到带消息头的消息如何处理
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HeaderPolicy {
+    #[default]
+    Strip, // 去掉消息头,只投递消息体
+    Reject, // 不投递给这个订阅者
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HeaderMap {
+    pub status: Option<u16>,
+    pub description: Option<String>,
+    entries: Vec<(String, String)>,
+}
+
+impl HeaderMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /**
+     * 解析消息头,buf必须是完整的头部,以空行结束
+     */
+    pub fn parse(buf: &[u8]) -> Result<Self> {
+        let s = std::str::from_utf8(buf).map_err(|_| NError::new(ERROR_INVALID_HEADER))?;
+        let body = match s.strip_suffix("\r\n\r\n") {
+            Some(body) => body,
+            None => return Err(NError::new(ERROR_INVALID_HEADER)),
+        };
+        let mut lines = body.split("\r\n");
+        let mut map = HeaderMap::new();
+        let status_line = lines.next().unwrap_or_default();
+        let rest = match status_line.strip_prefix(HEADER_VERSION) {
+            Some(rest) => rest.trim(),
+            None => return Err(NError::new(ERROR_INVALID_HEADER)),
+        };
+        if !rest.is_empty() {
+            let (status, description) = match rest.split_once(' ') {
+                Some((status, description)) => (status, Some(description.trim())),
+                None => (rest, None),
+            };
+            map.status = Some(
+                status
+                    .parse::<u16>()
+                    .map_err(|_| NError::new(ERROR_INVALID_HEADER))?,
+            );
+            map.description = description.map(|d| d.to_string());
+        }
+        for line in lines {
+            let (key, value) = match line.split_once(':') {
+                Some((key, value)) if !key.trim().is_empty() => (key.trim(), value.trim()),
+                _ => return Err(NError::new(ERROR_INVALID_HEADER)),
+            };
+            map.append(key, value);
+        }
+        Ok(map)
+    }
+
+    // key按照不区分大小写比较,返回第一个值
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn get_all<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+    }
+
+    // 替换掉同名的所有值
+    pub fn insert(&mut self, key: &str, value: &str) {
+        self.remove(key);
+        self.append(key, value);
+    }
+
+    pub fn append(&mut self, key: &str, value: &str) {
+        self.entries.push((key.to_string(), value.to_string()));
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        self.entries.retain(|(k, _)| !k.eq_ignore_ascii_case(key));
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    /**
+     * 序列化成HPUB/HMSG使用的头部格式
+     */
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = HEADER_VERSION.as_bytes().to_vec();
+        if let Some(status) = self.status {
+            buf.extend_from_slice(format!(" {}", status).as_bytes());
+            if let Some(ref description) = self.description {
+                buf.push(b' ');
+                buf.extend_from_slice(description.as_bytes());
+            }
+        }
+        buf.extend_from_slice(b"\r\n");
+        for (k, v) in self.entries.iter() {
+            buf.extend_from_slice(k.as_bytes());
+            buf.extend_from_slice(b": ");
+            buf.extend_from_slice(v.as_bytes());
+            buf.extend_from_slice(b"\r\n");
+        }
+        buf.extend_from_slice(b"\r\n");
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let map = HeaderMap::parse(
+            b"NATS/1.0\r\nTrace-Id: abc\r\ncontent-type: json\r\nTrace-Id: def\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(map.status, None);
+        assert_eq!(map.len(), 3);
+        assert_eq!(map.get("trace-id"), Some("abc"));
+        assert_eq!(
+            map.get_all("Trace-Id").collect::<Vec<_>>(),
+            vec!["abc", "def"]
+        );
+        assert_eq!(map.get("Content-Type"), Some("json"));
+        assert_eq!(map.get("missing"), None);
+    }
+
+    #[test]
+    fn test_status_and_roundtrip() {
+        let map = HeaderMap::parse(b"NATS/1.0 503 No Responders\r\n\r\n").unwrap();
+        assert_eq!(map.status, Some(503));
+        assert_eq!(map.description.as_deref(), Some("No Responders"));
+        assert!(map.is_empty());
+
+        let mut map = HeaderMap::new();
+        map.insert("A", "1");
+        map.append("A", "2");
+        map.insert("B", "3");
+        let bytes = map.to_bytes();
+        assert_eq!(bytes, b"NATS/1.0\r\nA: 1\r\nA: 2\r\nB: 3\r\n\r\n");
+        assert_eq!(HeaderMap::parse(&bytes).unwrap(), map);
+        map.insert("a", "4");
+        assert_eq!(map.iter().collect::<Vec<_>>(), vec![("B", "3"), ("a", "4")]);
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(HeaderMap::parse(b"HTTP/1.1\r\n\r\n").is_err());
+        assert!(HeaderMap::parse(b"NATS/1.0\r\nNoColon\r\n\r\n").is_err());
+        assert!(HeaderMap::parse(b"NATS/1.0\r\nA: 1\r\n").is_err());
+        assert!(HeaderMap::parse(b"NATS/1.0 abc\r\n\r\n").is_err());
+    }
+}
@@ -0,0 +1,152 @@
+use rand::{distributions::Alphanumeric, Rng};
+use serde_derive::{Deserialize, Serialize};
+
+/**
+ * 连接建立后服务端发送给客户端的INFO
+ * INFO {"server_id":"...","version":"0.1.0",...}\r\n
+ */
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ServerInfo {
+    pub server_id: String,
+    pub server_name: String,
+    pub version: String,
+    pub proto: i32,
+    pub host: String,
+    pub port: u16,
+    pub headers: bool,
+    pub max_payload: usize,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub auth_required: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub tls_required: bool, // 客户端收到INFO后需要发起TLS握手
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub tls_verify: bool, // 客户端需要提供证书
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub client_id: u64,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub client_ip: String,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub ldm: bool, // lame duck mode,客户端应该尽快重连到其他服务端
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub cluster: String, // 集群名字,只在路由的INFO中使用
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub connect_urls: Vec<String>, // 集群中所有服务端的客户端地址,客户端可以重连到其中任意一个
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub ip: String, // 路由地址,其他服务端通过路由的INFO得知后主动建立路由
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub gateway: String, // 网关所在集群的名字,只在网关的INFO中使用
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub gateway_url: String, // 网关地址,对方据此建立反方向的连接
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub gateway_cmd: u8, // 网关切换账户的interest-only模式
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub gateway_cmd_payload: String,
+}
+
+// 服务端ID长度
+const SERVER_ID_LEN: usize = 22;
+
+fn is_zero<T: Default + PartialEq>(n: &T) -> bool {
+    *n == T::default()
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
+impl Default for ServerInfo {
+    fn default() -> Self {
+        let server_id: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(SERVER_ID_LEN)
+            .map(char::from)
+            .collect::<String>()
+            .to_uppercase();
+        Self {
+            server_name: server_id.clone(),
+            server_id,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            proto: 1,
+            host: String::new(),
+            port: 0,
+            headers: true,
+            max_payload: 1024 * 1024,
+            auth_required: false,
+            tls_required: false,
+            tls_verify: false,
+            client_id: 0,
+            client_ip: String::new(),
+            ldm: false,
+            cluster: String::new(),
+            connect_urls: Vec::new(),
+            ip: String::new(),
+            gateway: String::new(),
+            gateway_url: String::new(),
+            gateway_cmd: 0,
+            gateway_cmd_payload: String::new(),
+        }
+    }
+}
+
+impl ServerInfo {
+    /**
+     * 序列化成协议格式 INFO {json}\r\n
+     */
+    pub fn to_protocol(&self) -> Vec<u8> {
+        let mut buf = b"INFO ".to_vec();
+        // 只包含基本类型的结构体序列化不会失败
+        buf.extend_from_slice(&serde_json::to_vec(self).unwrap());
+        buf.extend_from_slice(b"\r\n");
+        buf
+    }
+}
+
+/**
+ * 客户端CONNECT时携带的选项
+ * CONNECT {"verbose":false,"pedantic":false,"name":"...","lang":"rust",...}\r\n
+ */
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ConnectOptions {
+    pub verbose: bool,
+    pub pedantic: bool,
+    pub tls_required: bool,
+    pub name: Option<String>,
+    pub lang: Option<String>,
+    pub version: Option<String>,
+    pub protocol: i32,
+    pub headers: bool,
+    pub user: Option<String>,
+    pub pass: Option<String>,
+    pub auth_token: Option<String>,
+    pub gateway: Option<String>, // 网关连接时带上自己所在集群的名字
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_info_protocol() {
+        let info = ServerInfo::default();
+        assert_eq!(info.server_id.len(), SERVER_ID_LEN);
+        let buf = info.to_protocol();
+        assert!(buf.starts_with(b"INFO {"));
+        assert!(buf.ends_with(b"}\r\n"));
+        let parsed: ServerInfo = serde_json::from_slice(&buf[5..buf.len() - 2]).unwrap();
+        assert_eq!(parsed, info);
+    }
+
+    #[test]
+    fn test_connect_options() {
+        let opts: ConnectOptions = serde_json::from_str(
+            r#"{"verbose":true,"name":"svc","lang":"go","version":"1.2","headers":true,"echo":true}"#,
+        )
+        .unwrap();
+        assert!(opts.verbose);
+        assert!(!opts.pedantic);
+        assert!(opts.headers);
+        assert_eq!(opts.name.as_deref(), Some("svc"));
+        assert_eq!(opts.lang.as_deref(), Some("go"));
+    }
+}
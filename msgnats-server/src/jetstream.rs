@@ -0,0 +1,565 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use log::{debug, info, warn};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::fs;
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio::sync::{watch, Mutex};
+
+use crate::account::GLOBAL_ACCOUNT;
+use crate::client::{publish, ClientMessageSender, InternalMessage};
+use crate::errors::Result as NResult;
+use crate::parser::PubArg;
+use crate::server::ServerState;
+use crate::simple_sublist::{ArcSubscription, SubListTrait, SubScription};
+use crate::stream::{Stream, StreamConfig};
+use crate::trie_sublist::{subjects_overlap, validate_subject};
+
+/**
+JetStream配置,配置后服务端保存stream中的消息:
+```text
+jetstream {
+    store_dir: "/data/msgnats/jetstream"
+}
+```
+stream是服务端内部的订阅,和客户端的订阅一样通过路由、叶子节点和网关传播,
+集群中其他服务端上发布的消息也会保存.每个账户有自己的stream,通过账户中的请求主题管理,
+请求和响应都是JSON,出错时响应{"error":{"code":404,"description":"stream not found"}}
+```text
+$JS.API.STREAM.CREATE.<name>   {"subjects":["orders.>"],"max_msgs":1000,"max_bytes":0,"max_age":"24h"}
+$JS.API.STREAM.UPDATE.<name>   和CREATE相同,修改主题和限制
+$JS.API.STREAM.INFO.<name>     {"config":{..},"created":<纳秒>,"state":{"messages":1,..}}
+$JS.API.STREAM.DELETE.<name>   {"success":true}
+$JS.API.STREAM.PURGE.<name>    {"success":true,"purged":1}
+$JS.API.STREAM.NAMES           {"streams":["ORDERS"]}
+$JS.API.STREAM.MSG.GET.<name>  {"seq":1} -> {"message":{"subject":..,"seq":1,"time":<纳秒>,"data":<base64>}}
+```
+保存的消息带reply_to时回复{"stream":"ORDERS","seq":1}.
+同一个账户中的stream主题不能重叠,集群中只应该有一个服务端配置jetstream.
+*/
+pub const JS_API_PREFIX: &str = "$JS.API.";
+// stream的主题不能和这个主题重叠,包括>和*.API.>这样的通配符,避免保存API请求
+pub const JS_SUBJECTS: &str = "$JS.>";
+// 检查max_age的间隔
+pub const EXPIRE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct JetStreamConfig {
+    pub store_dir: PathBuf, // 每个账户一个子目录
+}
+
+impl Default for JetStreamConfig {
+    fn default() -> Self {
+        Self {
+            store_dir: std::env::temp_dir().join("msgnats").join("jetstream"),
+        }
+    }
+}
+
+/**
+ * API请求的错误,code和HTTP状态码的含义相同
+ */
+#[derive(Debug, PartialEq)]
+pub struct ApiError {
+    pub code: u16,
+    pub description: String,
+}
+
+impl ApiError {
+    fn new(code: u16, description: &str) -> Self {
+        Self {
+            code,
+            description: description.to_string(),
+        }
+    }
+
+    fn not_found() -> Self {
+        Self::new(404, "stream not found")
+    }
+
+    fn internal<E: std::fmt::Display>(e: E) -> Self {
+        Self::new(500, &e.to_string())
+    }
+
+    fn to_json(&self) -> Value {
+        json!({"error": {"code": self.code, "description": self.description}})
+    }
+}
+
+type ApiResult<T> = std::result::Result<T, ApiError>;
+
+#[derive(Debug, Deserialize)]
+struct MsgGetRequest {
+    seq: u64,
+}
+
+// 内部订阅收到的消息的用途
+#[derive(Debug)]
+enum Target {
+    Api(String),            // 账户的API请求
+    Stream(String, String), // (账户, stream名字)
+}
+
+#[derive(Debug)]
+struct StreamEntry {
+    stream: Stream,
+    subs: Vec<ArcSubscription>,
+}
+
+/**
+ * 所有的stream以及API请求都在一个任务中处理,内部订阅收到的消息通过channel发送过来
+ */
+pub struct JetStream<T: SubListTrait> {
+    state: Arc<Mutex<ServerState<T>>>,
+    store_dir: PathBuf,
+    sender: Arc<Mutex<ClientMessageSender>>, // 所有内部订阅共用
+    msg_rx: UnboundedReceiver<InternalMessage>,
+    streams: HashMap<(String, String), StreamEntry>, // (账户, stream名字) -> stream
+    targets: HashMap<String, Target>,                // 内部订阅的sid -> 用途
+    gen_sid: u64,
+}
+
+impl<T: SubListTrait + Default + Send + 'static> JetStream<T> {
+    /**
+     * 订阅每个账户的API主题,并且恢复store_dir中已经存在的stream
+     */
+    pub async fn start(
+        state: Arc<Mutex<ServerState<T>>>,
+        config: &JetStreamConfig,
+    ) -> Result<Self, Box<dyn Error>> {
+        fs::create_dir_all(&config.store_dir).await?;
+        let (msg_tx, msg_rx) = mpsc::unbounded_channel();
+        let mut sender = ClientMessageSender::new(tokio::io::sink());
+        sender.internal_tx = Some(msg_tx);
+        sender.headers = true;
+        let accounts: Vec<String> = {
+            let state = state.lock().await;
+            let configured = state.config.accounts.keys().cloned();
+            std::iter::once(GLOBAL_ACCOUNT.to_string())
+                .chain(configured)
+                .collect()
+        };
+        let mut jetstream = Self {
+            state,
+            store_dir: config.store_dir.clone(),
+            sender: Arc::new(Mutex::new(sender)),
+            msg_rx,
+            streams: HashMap::new(),
+            targets: HashMap::new(),
+            gen_sid: 0,
+        };
+        for account in accounts.iter() {
+            let api = format!("{}>", JS_API_PREFIX);
+            jetstream
+                .subscribe(Target::Api(account.clone()), account, &api)
+                .await?;
+            jetstream.load_streams(account).await?;
+        }
+        Ok(jetstream)
+    }
+
+    // 无法恢复的stream跳过,不影响服务端启动
+    async fn load_streams(&mut self, account: &str) -> Result<(), Box<dyn Error>> {
+        let dir = self.store_dir.join(account).join("streams");
+        let mut entries = match fs::read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let stream = match Stream::open(&entry.path()).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("load stream {:?} error:{}", entry.path(), e);
+                    continue;
+                }
+            };
+            info!(
+                "restored stream {} in account {}, {} messages",
+                stream.config.name,
+                account,
+                stream.state().messages
+            );
+            let subs = self.subscribe_stream(account, &stream.config).await?;
+            let key = (account.to_string(), stream.config.name.clone());
+            self.streams.insert(key, StreamEntry { stream, subs });
+        }
+        Ok(())
+    }
+
+    /**
+     * 处理内部订阅收到的消息,服务端停止时先处理完已经收到的消息
+     */
+    pub async fn run(mut self, mut stop_rx: watch::Receiver<bool>) {
+        let mut expire_timer = tokio::time::interval(EXPIRE_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = stop_rx.changed() => break,
+                _ = expire_timer.tick() => self.expire().await,
+                Some(msg) = self.msg_rx.recv() => self.process_msg(msg).await,
+            }
+        }
+        while let Ok(msg) = self.msg_rx.try_recv() {
+            self.process_msg(msg).await;
+        }
+    }
+
+    async fn process_msg(&mut self, msg: InternalMessage) {
+        match self.targets.get(&msg.sid) {
+            Some(Target::Api(account)) => {
+                let account = account.clone();
+                self.process_api(&account, msg).await;
+            }
+            Some(Target::Stream(account, name)) => {
+                let key = (account.clone(), name.clone());
+                self.store_msg(key, msg).await;
+            }
+            None => {} // 已经删除的stream
+        }
+    }
+
+    async fn store_msg(&mut self, key: (String, String), msg: InternalMessage) {
+        let entry = match self.streams.get_mut(&key) {
+            Some(entry) => entry,
+            None => return,
+        };
+        let response = match entry
+            .stream
+            .store(&msg.subject, msg.hdr_len, &msg.msg)
+            .await
+        {
+            Ok(seq) => json!({"stream": key.1, "seq": seq}),
+            Err(e) => {
+                warn!("stream {} store message error:{}", key.1, e);
+                ApiError::internal(e).to_json()
+            }
+        };
+        if let Some(reply_to) = msg.reply_to {
+            self.respond(&key.0, &reply_to, &response).await;
+        }
+    }
+
+    async fn process_api(&mut self, account: &str, msg: InternalMessage) {
+        let api = msg.subject.strip_prefix(JS_API_PREFIX).unwrap_or_default();
+        let payload = &msg.msg[msg.hdr_len.unwrap_or(0)..];
+        debug!("jetstream api {} in account {}", api, account);
+        let response = match self.handle_api(account, api, payload).await {
+            Ok(response) => response,
+            Err(e) => e.to_json(),
+        };
+        if let Some(ref reply_to) = msg.reply_to {
+            self.respond(account, reply_to, &response).await;
+        }
+    }
+
+    async fn handle_api(&mut self, account: &str, api: &str, payload: &[u8]) -> ApiResult<Value> {
+        let tokens: Vec<&str> = api.split('.').collect();
+        match tokens.as_slice() {
+            ["STREAM", "NAMES"] => {
+                let mut names: Vec<&String> = self
+                    .streams
+                    .keys()
+                    .filter(|(a, _)| a == account)
+                    .map(|(_, name)| name)
+                    .collect();
+                names.sort();
+                Ok(json!({ "streams": names }))
+            }
+            ["STREAM", "CREATE", name] => self.create_stream(account, name, payload).await,
+            ["STREAM", "UPDATE", name] => self.update_stream(account, name, payload).await,
+            ["STREAM", "INFO", name] => {
+                let entry = self.stream_mut(account, name)?;
+                entry
+                    .stream
+                    .enforce_limits()
+                    .await
+                    .map_err(ApiError::internal)?;
+                Ok(stream_info(&entry.stream))
+            }
+            ["STREAM", "DELETE", name] => {
+                let key = (account.to_string(), name.to_string());
+                let entry = self.streams.remove(&key).ok_or_else(ApiError::not_found)?;
+                self.unsubscribe(account, entry.subs).await;
+                entry.stream.delete().await.map_err(ApiError::internal)?;
+                info!("deleted stream {} in account {}", name, account);
+                Ok(json!({"success": true}))
+            }
+            ["STREAM", "PURGE", name] => {
+                let entry = self.stream_mut(account, name)?;
+                let purged = entry.stream.purge().await.map_err(ApiError::internal)?;
+                Ok(json!({"success": true, "purged": purged}))
+            }
+            ["STREAM", "MSG", "GET", name] => {
+                let request: MsgGetRequest = serde_json::from_slice(payload)
+                    .map_err(|e| ApiError::new(400, &format!("invalid request: {}", e)))?;
+                let entry = self.stream_mut(account, name)?;
+                let msg = entry.stream.get(request.seq).await;
+                let msg = msg
+                    .map_err(ApiError::internal)?
+                    .ok_or_else(|| ApiError::new(404, "no message found"))?;
+                let hdr_len = msg.hdr_len.unwrap_or(0);
+                let mut message = json!({
+                    "subject": msg.subject,
+                    "seq": msg.seq,
+                    "time": msg.time,
+                    "data": BASE64.encode(&msg.msg[hdr_len..]),
+                });
+                if hdr_len > 0 {
+                    message["hdrs"] = Value::from(BASE64.encode(&msg.msg[..hdr_len]));
+                }
+                Ok(json!({ "message": message }))
+            }
+            _ => Err(ApiError::new(400, "unknown api request")),
+        }
+    }
+
+    fn stream_mut(&mut self, account: &str, name: &str) -> ApiResult<&mut StreamEntry> {
+        let key = (account.to_string(), name.to_string());
+        self.streams.get_mut(&key).ok_or_else(ApiError::not_found)
+    }
+
+    async fn create_stream(
+        &mut self,
+        account: &str,
+        name: &str,
+        payload: &[u8],
+    ) -> ApiResult<Value> {
+        let config = parse_config(name, payload)?;
+        let key = (account.to_string(), name.to_string());
+        if self.streams.contains_key(&key) {
+            return Err(ApiError::new(400, "stream name already in use"));
+        }
+        self.check_overlap(account, &config)?;
+        let dir = self.store_dir.join(account).join("streams").join(name);
+        let stream = Stream::create(&dir, config)
+            .await
+            .map_err(ApiError::internal)?;
+        let subs = match self.subscribe_stream(account, &stream.config).await {
+            Ok(subs) => subs,
+            Err(e) => {
+                // 没有加入streams的stream不能留在磁盘上,否则重启后会被加载
+                if let Err(e) = stream.delete().await {
+                    warn!("delete stream {} error:{}", name, e);
+                }
+                return Err(ApiError::internal(e));
+            }
+        };
+        info!("created stream {} in account {}", name, account);
+        let info = stream_info(&stream);
+        self.streams.insert(key, StreamEntry { stream, subs });
+        Ok(info)
+    }
+
+    /**
+     * 主题变化时先订阅新的主题,订阅失败时stream和原来的订阅都保持不变,
+     * 更新配置后保留和stream当前主题一致的那组订阅,取消另一组
+     */
+    async fn update_stream(
+        &mut self,
+        account: &str,
+        name: &str,
+        payload: &[u8],
+    ) -> ApiResult<Value> {
+        let config = parse_config(name, payload)?;
+        let subjects = config.subjects.clone();
+        let resubscribe = self.stream_mut(account, name)?.stream.config.subjects != subjects;
+        self.check_overlap(account, &config)?;
+        let subs = if resubscribe {
+            let subs = self.subscribe_stream(account, &config).await;
+            Some(subs.map_err(ApiError::internal)?)
+        } else {
+            None
+        };
+        let entry = self.stream_mut(account, name)?;
+        let r = entry.stream.update(config).await;
+        let info = stream_info(&entry.stream);
+        if let Some(subs) = subs {
+            let stale = if entry.stream.config.subjects == subjects {
+                std::mem::replace(&mut entry.subs, subs)
+            } else {
+                subs
+            };
+            self.unsubscribe(account, stale).await;
+        }
+        r.map_err(ApiError::internal)?;
+        Ok(info)
+    }
+
+    // 同一个账户中其他stream的主题不能和config的重叠
+    fn check_overlap(&self, account: &str, config: &StreamConfig) -> ApiResult<()> {
+        let others = self
+            .streams
+            .iter()
+            .filter(|((a, name), _)| a == account && *name != config.name)
+            .flat_map(|(_, entry)| entry.stream.config.subjects.iter());
+        for subject in others {
+            if config.subjects.iter().any(|s| subjects_overlap(s, subject)) {
+                return Err(ApiError::new(
+                    400,
+                    "subjects overlap with an existing stream",
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // 某个主题订阅失败时取消已经订阅的主题
+    async fn subscribe_stream(
+        &mut self,
+        account: &str,
+        config: &StreamConfig,
+    ) -> NResult<Vec<ArcSubscription>> {
+        let mut subs = Vec::new();
+        for subject in config.subjects.iter() {
+            let target = Target::Stream(account.to_string(), config.name.clone());
+            match self.subscribe(target, account, subject).await {
+                Ok(sub) => subs.push(sub),
+                Err(e) => {
+                    self.unsubscribe(account, subs).await;
+                    return Err(e);
+                }
+            }
+        }
+        Ok(subs)
+    }
+
+    async fn subscribe(
+        &mut self,
+        target: Target,
+        account: &str,
+        subject: &str,
+    ) -> NResult<ArcSubscription> {
+        self.gen_sid += 1;
+        let sid = self.gen_sid.to_string();
+        let sub = Arc::new(SubScription::new(self.sender.clone(), subject, None, &sid));
+        self.state.lock().await.insert_sub(account, sub.clone())?;
+        self.targets.insert(sid, target);
+        Ok(sub)
+    }
+
+    async fn unsubscribe(&mut self, account: &str, subs: Vec<ArcSubscription>) {
+        let mut state = self.state.lock().await;
+        for sub in subs {
+            self.targets.remove(&sub.sid);
+            if let Err(e) = state.remove_sub(account, sub) {
+                warn!("jetstream remove sub error:{}", e);
+            }
+        }
+    }
+
+    // 删除超过max_age的消息
+    async fn expire(&mut self) {
+        let streams = self.streams.values_mut();
+        for entry in streams.filter(|e| !e.stream.config.max_age.is_zero()) {
+            if let Err(e) = entry.stream.enforce_limits().await {
+                warn!("stream {} expire error:{}", entry.stream.config.name, e);
+            }
+        }
+    }
+
+    // 在account中发布响应
+    async fn respond(&self, account: &str, reply_to: &str, response: &Value) {
+        let msg = response.to_string();
+        let size = msg.len().to_string();
+        let pub_arg = PubArg {
+            subject: reply_to,
+            reply_to: None,
+            hdr_len: None,
+            size_buf: &size,
+            size: msg.len(),
+            msg: msg.as_bytes(),
+        };
+        if let Err(e) = publish(&self.state, account, &pub_arg).await {
+            debug!("jetstream respond to {} error:{}", reply_to, e);
+        }
+    }
+}
+
+fn stream_info(stream: &Stream) -> Value {
+    json!({
+        "config": stream.config,
+        "created": stream.created,
+        "state": stream.state(),
+    })
+}
+
+// stream的名字同时也是目录名,不能包含主题分隔符、通配符以及路径分隔符
+fn validate_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name
+            .chars()
+            .any(|c| c.is_whitespace() || matches!(c, '.' | '*' | '>' | '/' | '\\'))
+}
+
+/**
+ * 解析CREATE和UPDATE请求中的配置,配置中的名字可以省略,主题为空时使用名字
+ */
+fn parse_config(name: &str, payload: &[u8]) -> ApiResult<StreamConfig> {
+    let mut config: StreamConfig = if payload.is_empty() {
+        StreamConfig::default()
+    } else {
+        serde_json::from_slice(payload)
+            .map_err(|e| ApiError::new(400, &format!("invalid stream config: {}", e)))?
+    };
+    if config.name.is_empty() {
+        config.name = name.to_string();
+    }
+    if config.name != name {
+        return Err(ApiError::new(
+            400,
+            "stream name in subject does not match request",
+        ));
+    }
+    if !validate_name(name) {
+        return Err(ApiError::new(400, "invalid stream name"));
+    }
+    if config.subjects.is_empty() {
+        config.subjects = vec![name.to_string()];
+    }
+    for subject in config.subjects.iter() {
+        if validate_subject(subject).is_err() || subjects_overlap(subject, JS_SUBJECTS) {
+            return Err(ApiError::new(
+                400,
+                &format!("invalid stream subject {}", subject),
+            ));
+        }
+    }
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let config = parse_config("ORDERS", b"").unwrap();
+        assert_eq!(config.subjects, vec!["ORDERS"]);
+        let config = parse_config(
+            "ORDERS",
+            br#"{"subjects":["orders.>"],"max_msgs":10,"max_age":"1h"}"#,
+        )
+        .unwrap();
+        assert_eq!(config.name, "ORDERS");
+        assert_eq!(config.max_msgs, 10);
+        assert_eq!(config.max_age, Duration::from_secs(3600));
+
+        let err = |name: &str, payload: &[u8]| parse_config(name, payload).unwrap_err().code;
+        assert_eq!(err("ORDERS", br#"{"name":"OTHER"}"#), 400);
+        assert_eq!(err("a.b", b""), 400);
+        assert_eq!(err("..", b""), 400);
+        assert_eq!(err("X", br#"{"subjects":["$JS.API.>"]}"#), 400);
+        assert_eq!(err("X", br#"{"subjects":[">"]}"#), 400);
+        assert_eq!(err("X", br#"{"subjects":["*.API.>"]}"#), 400);
+        assert_eq!(err("X", br#"{"subjects":["a..b"]}"#), 400);
+        assert_eq!(err("X", b"{"), 400);
+    }
+}
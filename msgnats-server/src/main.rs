@@ -1,18 +1,96 @@
 use std::error::Error;
 
-use simple_sublist::SimpleSubList;
+use cache_sublist::CacheSubList;
+use cli::{Options, USAGE};
+use config::ServerConfig;
+use trie_sublist::TrieSubList;
 
 use crate::server::Server;
+pub mod account;
+pub mod auth;
+pub mod cache_sublist;
+pub mod cli;
 pub mod client;
+pub mod conf_parser;
+pub mod config;
 pub mod errors;
+pub mod gateway;
+pub mod headers;
+pub mod info;
+pub mod jetstream;
+pub mod leaf;
 pub mod parser;
+pub mod queue;
+pub mod route;
 pub mod server;
 pub mod simple_sublist;
+pub mod stream;
+pub mod tls;
+pub mod trie_sublist;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    println!("server is begin start......");
-    let server: Server<SimpleSubList> = Server::default();
-    server.start().await?;
+    let opts = match Options::parse(std::env::args().skip(1)) {
+        Ok(opts) => opts,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+    if opts.help {
+        print!("{}", USAGE);
+        return Ok(());
+    }
+    if opts.version {
+        println!("msgnats-server {}", env!("CARGO_PKG_VERSION"));
+        return Ok(());
+    }
+    // 先加载配置文件,再用命令行参数覆盖
+    let mut config = match opts.config {
+        Some(ref path) => ServerConfig::from_file(path)?,
+        None => ServerConfig::default(),
+    };
+    opts.apply(&mut config);
+    // 命令行参数覆盖之后的配置需要再检查一次
+    config.validate().map_err(|(_, message)| message)?;
+    env_logger::Builder::new()
+        .parse_filters(&config.log_level)
+        .init();
+    if let Some(ref pid_file) = config.pid_file {
+        std::fs::write(pid_file, std::process::id().to_string())?;
+    }
+    log::info!("starting msgnats-server {}", env!("CARGO_PKG_VERSION"));
+    let pid_file = config.pid_file.clone();
+    let server: Server<CacheSubList<TrieSubList>> = Server::new(CacheSubList::default(), config);
+    let handle = server.start().await?;
+    match wait_for_signal().await? {
+        Signal::Shutdown => handle.shutdown().await,
+        Signal::LameDuck => handle.lame_duck().await,
+    }
+    if let Some(ref pid_file) = pid_file {
+        let _ = std::fs::remove_file(pid_file);
+    }
     Ok(())
 }
+
+enum Signal {
+    Shutdown, // SIGINT SIGTERM
+    LameDuck, // SIGUSR2
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() -> std::io::Result<Signal> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut lame_duck = signal(SignalKind::user_defined2())?;
+    tokio::select! {
+        r = tokio::signal::ctrl_c() => r.map(|_| Signal::Shutdown),
+        _ = terminate.recv() => Ok(Signal::Shutdown),
+        _ = lame_duck.recv() => Ok(Signal::LameDuck),
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() -> std::io::Result<Signal> {
+    tokio::signal::ctrl_c().await.map(|_| Signal::Shutdown)
+}
@@ -1,23 +1,48 @@
 // /**
 // * ## pub
 //  * ```
-//  * PUB <subject> <size>\r\n
+//  * PUB <subject> [reply-to] <size>\r\n
 //  * <message>\r\n
 //  * ```
+//  * ## hpub
+//  * ```
+//  * HPUB <subject> [reply-to] <hdr_len> <total_len>\r\n
+//  * <headers><message>\r\n
+//  * ```
 //  * ## sub
 //  * ```
 //  * SUB <subject> <sid>\r\n
 //  * SUB <subject> <queue> <sid>\r\n
 //  * ```
+//  * ## unsub
+//  * ```
+//  * UNSUB <sid> [max_msgs]\r\n
+//  * ```
+//  * ## ping/pong
+//  * ```
+//  * PING\r\n
+//  * PONG\r\n
+//  * ```
+//  * ## connect
+//  * ```
+//  * CONNECT {json}\r\n
+//  * ```
 //  * ## MSG
 //  * ```
-//  * MSG <subject> <sid> <size>\r\n
+//  * MSG <subject> <sid> [reply-to] <size>\r\n
 //  * <message>\r\n
+//  * ```
+//  * ## HMSG
+//  * ```
+//  * HMSG <subject> <sid> [reply-to] <hdr_len> <total_len>\r\n
+//  * <headers><message>\r\n
 // *
 // **/
+use crate::config::{DEFAULT_MAX_CONTROL_LINE, DEFAULT_MAX_PAYLOAD};
 use crate::errors::{
-    NError, Result, ERROR_MESSAGE_NONE, ERROR_MESSAGE_SIZE_TOO_LARGE, ERROR_PARSE,
+    NError, Result, ERROR_MAX_CONTROL_LINE, ERROR_MESSAGE_SIZE_TOO_LARGE, ERROR_PARSE,
 };
+use crate::info::ConnectOptions;
 
 // 定义错误宏
 #[macro_export]
@@ -42,6 +67,34 @@ enum ParseState {
     OpPubArg,
     OpMsg, //pub message
     OpMsgFull,
+    OpU,
+    OpUn,
+    OpUns,
+    OpUnsu,
+    OpUnsub,
+    OpUnsubSpace,
+    OpUnsubArg,
+    OpPi,
+    OpPin,
+    OpPing,
+    OpPo,
+    OpPon,
+    OpPong,
+    OpC,
+    OpCo,
+    OpCon,
+    OpConn,
+    OpConne,
+    OpConnec,
+    OpConnect,
+    OpConnectSpace,
+    OpConnectArg,
+    OpH,
+    OpHp,
+    OpHpu,
+    OpHpub,
+    OpHpubSpace,
+    OpHpubArg,
 }
 
 // 解析结果定义
@@ -54,42 +107,81 @@ pub struct SubArg<'a> {
 #[derive(Debug, PartialEq)]
 pub struct PubArg<'a> {
     pub subject: &'a str,
-    pub size_buf: &'a str, // str字符串切片形式避免内存复制
+    pub reply_to: Option<&'a str>, // 请求/响应模式中订阅者回复的主题
+    pub hdr_len: Option<usize>,    // HPUB时消息头的长度,消息头在msg的最前面
+    pub size_buf: &'a str,         // str字符串切片形式避免内存复制
     pub size: usize,
     pub msg: &'a [u8],
 }
+
+impl<'a> PubArg<'a> {
+    // HPUB携带的消息头部分
+    pub fn headers(&self) -> Option<&'a [u8]> {
+        self.hdr_len.map(|hdr_len| &self.msg[..hdr_len])
+    }
+
+    // 去掉消息头之后的消息体
+    pub fn payload(&self) -> &'a [u8] {
+        &self.msg[self.hdr_len.unwrap_or(0)..]
+    }
+}
+#[derive(Debug, PartialEq)]
+pub struct UnsubArg<'a> {
+    pub sid: &'a str,
+    pub max_msgs: Option<usize>, // 收到max_msgs条消息后自动取消订阅
+}
 #[derive(Debug, PartialEq)]
 pub enum ParseResult<'a> {
     NoMsg, // buf="sub top.stevenbai.blog" sub消息格式不完整
     SubArg(SubArg<'a>),
     PubArg(PubArg<'a>),
+    UnsubArg(UnsubArg<'a>),
+    Ping,
+    Pong,
+    ConnectArg(ConnectOptions),
 }
 
 // 解析器数据结构定义
-const DEFAULT_BUF_LEN: usize = 512; // 默认解析缓冲区大小为512
 
 pub struct Parser {
     state: ParseState,
-    buf: [u8; DEFAULT_BUF_LEN], // 默认缓冲区大小512 超过另行分配使用msg_buf
+    buf: Vec<u8>, // 控制行缓冲区,大小为max_control_line,消息体比较短时也放在这里,超过另行分配使用msg_buf
     arg_len: usize,
     msg_buf: Option<Vec<u8>>,
     //解析过程中收到新消息,那么 新消息的总长度是msg_total_len,已收到部分应该是msg_len
     msg_total_len: usize,
     msg_len: usize,
+    hdr_len: Option<usize>, // 正在解析的是HPUB时消息头的长度
+    max_payload: usize,
     debug: bool,
 }
 
 // TODO：解析器实现
 
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Parser {
     pub fn new() -> Self {
+        Self::with_limits(DEFAULT_MAX_CONTROL_LINE, DEFAULT_MAX_PAYLOAD)
+    }
+
+    /**
+     * max_control_line限制主题以及参数的长度,max_payload限制消息体的长度
+     */
+    pub fn with_limits(max_control_line: usize, max_payload: usize) -> Self {
         Self {
             state: ParseState::OpStart,
-            buf: [0; DEFAULT_BUF_LEN],
+            buf: vec![0; max_control_line],
             arg_len: 0,
             msg_buf: None,
             msg_total_len: 0,
             msg_len: 0,
+            hdr_len: None,
+            max_payload,
             debug: false,
         }
     }
@@ -99,16 +191,16 @@ impl Parser {
     //  * 对收到的字节序列进行解析,解析完毕后得到pub或者sub消息,
     //  * 同时有可能没有消息或者缓冲区里面还有其他消息
     //  */
-    pub fn parse(&mut self, buf: &[u8]) -> Result<(ParseResult, usize)> {
+    pub fn parse(&mut self, buf: &[u8]) -> Result<(ParseResult<'_>, usize)> {
         // 定义字节数据接收变量
         let mut b;
         // buf字节序列循环变量
         let mut i = 0;
         // 打印 debug日志
         if self.debug {
-            print!(
+            log::trace!(
                 "parse string:{},state:{:?}",
-                unsafe { std::str::from_utf8_unchecked(buf) },
+                String::from_utf8_lossy(buf),
                 self.state
             )
         }
@@ -121,8 +213,48 @@ impl Parser {
                 OpStart => match b {
                     'S' => self.state = OpS,
                     'P' => self.state = OpP,
+                    'U' => self.state = OpU,
+                    'C' => self.state = OpC,
+                    'H' => self.state = OpH,
                     _ => parse_error!(),
                 },
+                OpH => match b {
+                    'P' => self.state = OpHp,
+                    _ => parse_error!(),
+                },
+                OpHp => match b {
+                    'U' => self.state = OpHpu,
+                    _ => parse_error!(),
+                },
+                OpHpu => match b {
+                    'B' => self.state = OpHpub,
+                    _ => parse_error!(),
+                },
+                OpHpub => match b {
+                    ' ' | '\t' => self.state = OpHpubSpace,
+                    _ => parse_error!(),
+                },
+                OpHpubSpace => match b {
+                    ' ' | '\t' => {}
+                    _ => {
+                        self.state = OpHpubArg;
+                        self.arg_len = 0;
+                        continue;
+                    }
+                },
+                OpHpubArg => match b {
+                    '\r' => {}
+                    '\n' => {
+                        //HPUB top.stevenbai 12 17\r\n
+                        self.state = OpMsg;
+                        let (hdr_len, size) = self.get_hmessage_size()?;
+                        self.start_msg(size)?;
+                        self.hdr_len = Some(hdr_len);
+                    }
+                    _ => {
+                        self.add_arg(b as u8)?;
+                    }
+                },
                 OpS => match b {
                     'U' => self.state = OpSu,
                     _ => parse_error!(),
@@ -160,8 +292,90 @@ impl Parser {
                     'U' => {
                         self.state = OpPu;
                     }
+                    'I' => self.state = OpPi,
+                    'O' => self.state = OpPo,
                     _ => parse_error!(),
                 },
+                OpPi => match b {
+                    'N' => self.state = OpPin,
+                    _ => parse_error!(),
+                },
+                OpPin => match b {
+                    'G' => self.state = OpPing,
+                    _ => parse_error!(),
+                },
+                OpPing => match b {
+                    ' ' | '\t' | '\r' => {}
+                    '\n' => {
+                        self.state = OpStart;
+                        return Ok((ParseResult::Ping, i + 1));
+                    }
+                    _ => parse_error!(),
+                },
+                OpPo => match b {
+                    'N' => self.state = OpPon,
+                    _ => parse_error!(),
+                },
+                OpPon => match b {
+                    'G' => self.state = OpPong,
+                    _ => parse_error!(),
+                },
+                OpPong => match b {
+                    ' ' | '\t' | '\r' => {}
+                    '\n' => {
+                        self.state = OpStart;
+                        return Ok((ParseResult::Pong, i + 1));
+                    }
+                    _ => parse_error!(),
+                },
+                OpC => match b {
+                    'O' => self.state = OpCo,
+                    _ => parse_error!(),
+                },
+                OpCo => match b {
+                    'N' => self.state = OpCon,
+                    _ => parse_error!(),
+                },
+                OpCon => match b {
+                    'N' => self.state = OpConn,
+                    _ => parse_error!(),
+                },
+                OpConn => match b {
+                    'E' => self.state = OpConne,
+                    _ => parse_error!(),
+                },
+                OpConne => match b {
+                    'C' => self.state = OpConnec,
+                    _ => parse_error!(),
+                },
+                OpConnec => match b {
+                    'T' => self.state = OpConnect,
+                    _ => parse_error!(),
+                },
+                OpConnect => match b {
+                    ' ' | '\t' => self.state = OpConnectSpace,
+                    _ => parse_error!(),
+                },
+                OpConnectSpace => match b {
+                    ' ' | '\t' => {}
+                    _ => {
+                        self.state = OpConnectArg;
+                        self.arg_len = 0;
+                        continue;
+                    }
+                },
+                OpConnectArg => match b {
+                    '\r' => {}
+                    '\n' => {
+                        //CONNECT {"verbose":false}\r\n
+                        self.state = OpStart;
+                        let r = self.process_connect()?;
+                        return Ok((r, i + 1));
+                    }
+                    _ => {
+                        self.add_arg(b as u8)?;
+                    }
+                },
                 OpPu => match b {
                     'B' => {
                         self.state = OpPub;
@@ -186,24 +400,10 @@ impl Parser {
                         //PUB top.stevenbai 5\r\n
                         self.state = OpMsg;
                         let size = self.get_message_size()?;
-                        // 空消息
-                        if size == 0 {
-                            return Err(NError::new(ERROR_MESSAGE_NONE));
-                        }
-                        //消息体长度不应该超过1M,防止Dos攻击
-                        if size > 1 * 1024 * 1024 {
-                            return Err(NError::new(ERROR_MESSAGE_SIZE_TOO_LARGE));
-                        }
-                        // 超过默认消息长度处理
-                        if size + self.arg_len > DEFAULT_BUF_LEN {
-                            if self.msg_buf.is_none() {
-                                self.msg_buf = Some(Vec::with_capacity(size));
-                            }
-                        }
-                        self.msg_total_len = size;
+                        self.start_msg(size)?;
                     }
                     _ => {
-                        self.add_arg(b as u8);
+                        self.add_arg(b as u8)?;
                     }
                 },
                 OpMsg => {
@@ -223,11 +423,69 @@ impl Parser {
                     }
                     _ => parse_error!(),
                 },
+                OpU => match b {
+                    'N' => self.state = OpUn,
+                    _ => parse_error!(),
+                },
+                OpUn => match b {
+                    'S' => self.state = OpUns,
+                    _ => parse_error!(),
+                },
+                OpUns => match b {
+                    'U' => self.state = OpUnsu,
+                    _ => parse_error!(),
+                },
+                OpUnsu => match b {
+                    'B' => self.state = OpUnsub,
+                    _ => parse_error!(),
+                },
+                OpUnsub => match b {
+                    ' ' | '\t' => self.state = OpUnsubSpace,
+                    _ => parse_error!(),
+                },
+                OpUnsubSpace => match b {
+                    ' ' | '\t' => {}
+                    _ => {
+                        self.state = OpUnsubArg;
+                        self.arg_len = 0;
+                        continue;
+                    }
+                },
+                OpUnsubArg => match b {
+                    '\r' => {}
+                    '\n' => {
+                        //UNSUB 1 5\r\n
+                        self.state = OpStart;
+                        let r = self.process_unsub()?;
+                        return Ok((r, i + 1));
+                    }
+                    _ => {
+                        self.add_arg(b as u8)?;
+                    }
+                },
             }
             i += 1;
         }
         Ok((ParseResult::NoMsg, buf.len()))
     }
+    // 新消息开始,丢弃上一条消息的状态
+    fn start_msg(&mut self, size: usize) -> Result<()> {
+        //消息体长度不应该超过max_payload,防止Dos攻击
+        if size > self.max_payload {
+            return Err(NError::new(ERROR_MESSAGE_SIZE_TOO_LARGE));
+        }
+        // 控制行缓冲区放不下时另行分配
+        self.msg_buf = if size + self.arg_len > self.buf.len() {
+            Some(Vec::with_capacity(size))
+        } else {
+            None
+        };
+        self.msg_total_len = size;
+        self.msg_len = 0;
+        self.hdr_len = None;
+        Ok(())
+    }
+
     //一种是消息体比较短,可以直接放在buf中,无需另外分配内存
     //另一种是消息体很长,无法放在buf中,额外分配了msg_buf空间
     fn add_msg(&mut self, b: u8) {
@@ -235,7 +493,7 @@ impl Parser {
             buf.push(b);
         } else {
             // 如果消息体比较短
-            if self.arg_len + self.msg_total_len > DEFAULT_BUF_LEN {
+            if self.arg_len + self.msg_total_len > self.buf.len() {
                 panic!("message should allocate space");
             }
             self.buf[self.arg_len + self.msg_len] = b;
@@ -246,14 +504,14 @@ impl Parser {
     fn add_arg(&mut self, b: u8) -> Result<()> {
         // 太长的subject
         if self.arg_len >= self.buf.len() {
-            parse_error!();
+            return Err(NError::new(ERROR_MAX_CONTROL_LINE));
         }
         self.buf[self.arg_len] = b;
         self.arg_len += 1;
         Ok(())
     }
     //解析缓冲区中的形如stevenbai.top queue 3
-    fn process_sub(&self) -> Result<ParseResult> {
+    fn process_sub(&self) -> Result<ParseResult<'_>> {
         let buf = &self.buf[0..self.arg_len];
         //有可能客户端恶意发送一些无效的utf8字符,这会导致错误.
         let ss = unsafe { std::str::from_utf8_unchecked(buf) };
@@ -261,9 +519,12 @@ impl Parser {
         let mut arg_len = 0;
 
         for s in ss.split(' ') {
-            if s.len() == 0 {
+            if s.is_empty() {
                 continue;
             }
+            if arg_len >= 3 {
+                parse_error!();
+            }
             arg_buf[arg_len] = s;
             arg_len += 1;
         }
@@ -289,45 +550,97 @@ impl Parser {
         Ok(ParseResult::SubArg(sub_arg))
     }
 
+    //解析缓冲区中的CONNECT选项,形如{"verbose":false,"name":"test"}
+    fn process_connect(&self) -> Result<ParseResult<'_>> {
+        let opts: ConnectOptions = serde_json::from_slice(&self.buf[0..self.arg_len])
+            .map_err(|_| NError::new(ERROR_PARSE))?;
+        Ok(ParseResult::ConnectArg(opts))
+    }
+
+    //解析缓冲区中的形如 3 或者 3 10
+    fn process_unsub(&self) -> Result<ParseResult<'_>> {
+        let ss = unsafe { std::str::from_utf8_unchecked(&self.buf[0..self.arg_len]) };
+        let mut args = ss.split([' ', '\t']).filter(|s| !s.is_empty());
+        let sid = match args.next() {
+            Some(sid) => sid,
+            None => parse_error!(),
+        };
+        let max_msgs = match args.next() {
+            Some(m) => Some(m.parse::<usize>().map_err(|_| NError::new(ERROR_PARSE))?),
+            None => None,
+        };
+        if args.next().is_some() {
+            parse_error!();
+        }
+        Ok(ParseResult::UnsubArg(UnsubArg { sid, max_msgs }))
+    }
+
     //解析缓冲区中以及msg_buf中的形如stevenbai.top 5hello
-    fn process_msg(&self) -> Result<ParseResult> {
-        let msg = if self.msg_buf.is_some() {
-            self.msg_buf.as_ref().unwrap().as_slice()
+    fn process_msg(&self) -> Result<ParseResult<'_>> {
+        let msg = if let Some(msg_buf) = self.msg_buf.as_ref() {
+            msg_buf.as_slice()
         } else {
-            &self.buf[self.arg_len..self.msg_len + self.msg_total_len]
+            &self.buf[self.arg_len..self.arg_len + self.msg_total_len]
         };
 
-        let mut arg_buf = [""; 2];
+        //如果没有reply-to,PUB长度就是2,否则长度是3;HPUB多一个hdr_len
+        let mut arg_buf = [""; 4];
         let mut arg_len = 0;
+        let hdr_args = usize::from(self.hdr_len.is_some());
 
         let ss = unsafe { std::str::from_utf8_unchecked(&self.buf[0..self.arg_len]) };
-        for s in ss.split(" ") {
-            if s.len() == 0 {
+        for s in ss.split([' ', '\t']) {
+            if s.is_empty() {
                 continue;
             }
 
-            if s.len() >= 2 {
+            if arg_len >= 3 + hdr_args {
                 parse_error!()
             }
             arg_buf[arg_len] = s;
             arg_len += 1;
         }
+        let reply_to = match arg_len - hdr_args {
+            2 => None,
+            3 => Some(arg_buf[1]),
+            _ => parse_error!(),
+        };
         let pub_arg = PubArg {
             subject: arg_buf[0],
-            size_buf: arg_buf[1],
+            reply_to,
+            hdr_len: self.hdr_len,
+            size_buf: arg_buf[arg_len - 1],
             size: self.msg_total_len,
             msg,
         };
         Ok(ParseResult::PubArg(pub_arg))
     }
+    //从接收到的hpub消息中提前解析出来消息头长度和消息总长度
+    fn get_hmessage_size(&self) -> Result<(usize, usize)> {
+        //缓冲区中形如top.stevenbai.top 12 17
+        let ss = unsafe { std::str::from_utf8_unchecked(&self.buf[0..self.arg_len]) };
+        let mut sizes = ss.rsplit([' ', '\t']).filter(|s| !s.is_empty());
+        let (total_len, hdr_len) = match (sizes.next(), sizes.next(), sizes.next()) {
+            (Some(total_len), Some(hdr_len), Some(_)) => (total_len, hdr_len),
+            _ => parse_error!(),
+        };
+        let total_len = total_len
+            .parse::<usize>()
+            .map_err(|_| NError::new(ERROR_PARSE))?;
+        let hdr_len = hdr_len
+            .parse::<usize>()
+            .map_err(|_| NError::new(ERROR_PARSE))?;
+        if hdr_len > total_len {
+            parse_error!();
+        }
+        Ok((hdr_len, total_len))
+    }
+
     //从接收到的pub消息中提前解析出来消息的长度
     fn get_message_size(&self) -> Result<usize> {
         //缓冲区中形如top.stevenbai.top 5
         let arg_buf = &self.buf[0..self.arg_len];
-        let pos = arg_buf
-            .iter()
-            .rev()
-            .position(|b| *b == ' ' as u8 || *b == '\t' as u8);
+        let pos = arg_buf.iter().rev().position(|b| *b == b' ' || *b == b'\t');
         if pos.is_none() {
             parse_error!();
         }
@@ -348,7 +661,7 @@ impl<'a> Iterator for ParseIter<'a> {
     type Item = Result<ParseResult<'a>>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.buf.len() == 0 {
+        if self.buf.is_empty() {
             return None;
         }
         /*
@@ -361,9 +674,220 @@ impl<'a> Iterator for ParseIter<'a> {
 
         let r: Result<(ParseResult<'a>, usize)> = parser.parse(self.buf);
 
-        return Some(r.map(|r| {
+        Some(r.map(|r| {
             self.buf = &self.buf[r.1..];
             r.0
-        }));
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sub() {
+        let mut p = Parser::new();
+        let mut buf = "SUB subject 1\r\nSUB subject2 queue 2\r\n".as_bytes();
+        let (r, n) = p.parse(buf).unwrap();
+        assert_eq!(
+            r,
+            ParseResult::SubArg(SubArg {
+                subject: "subject",
+                sid: "1",
+                queue: None
+            })
+        );
+        buf = &buf[n..];
+        let (r, n) = p.parse(buf).unwrap();
+        assert_eq!(
+            r,
+            ParseResult::SubArg(SubArg {
+                subject: "subject2",
+                sid: "2",
+                queue: Some("queue")
+            })
+        );
+        assert_eq!(n, buf.len());
+        assert!(p.parse(b"SUB a b c d\r\n").is_err());
+    }
+
+    #[test]
+    fn test_unsub() {
+        let mut p = Parser::new();
+        let buf = "UNSUB 1\r\nUNSUB  2 10\r\n".as_bytes();
+        let (r, n) = p.parse(buf).unwrap();
+        assert_eq!(
+            r,
+            ParseResult::UnsubArg(UnsubArg {
+                sid: "1",
+                max_msgs: None
+            })
+        );
+        let (r, _) = p.parse(&buf[n..]).unwrap();
+        assert_eq!(
+            r,
+            ParseResult::UnsubArg(UnsubArg {
+                sid: "2",
+                max_msgs: Some(10)
+            })
+        );
+        assert!(p.parse(b"UNSUB 1 x\r\n").is_err());
+        assert!(p.parse(b"UNSUB 1 2 3\r\n").is_err());
+    }
+
+    #[test]
+    fn test_ping_pong() {
+        let mut p = Parser::new();
+        let buf = "PING\r\nPONG\r\nPUB a 1\r\nx\r\n".as_bytes();
+        let (r, n) = p.parse(buf).unwrap();
+        assert_eq!(r, ParseResult::Ping);
+        let buf = &buf[n..];
+        let (r, n) = p.parse(buf).unwrap();
+        assert_eq!(r, ParseResult::Pong);
+        assert!(matches!(
+            p.parse(&buf[n..]).unwrap().0,
+            ParseResult::PubArg(_)
+        ));
+        assert!(p.parse(b"PINGX\r\n").is_err());
+    }
+
+    #[test]
+    fn test_connect() {
+        let mut p = Parser::new();
+        let buf = "CONNECT {\"verbose\":true,\"name\":\"a b\"}\r\nPING\r\n".as_bytes();
+        let (r, n) = p.parse(buf).unwrap();
+        match r {
+            ParseResult::ConnectArg(opts) => {
+                assert!(opts.verbose);
+                assert_eq!(opts.name.as_deref(), Some("a b"));
+            }
+            _ => panic!(),
+        }
+        assert_eq!(p.parse(&buf[n..]).unwrap().0, ParseResult::Ping);
+        assert!(p.parse(b"CONNECT {bad\r\n").is_err());
+    }
+
+    #[test]
+    fn test_pub() {
+        let mut p = Parser::new();
+        let buf = "PUB subject 5\r\nhello\r\nPUB subject 3\r\nabc\r\n".as_bytes();
+        let (r, n) = p.parse(buf).unwrap();
+        match r {
+            ParseResult::PubArg(pub_arg) => {
+                assert_eq!(pub_arg.subject, "subject");
+                assert_eq!(pub_arg.size_buf, "5");
+                assert_eq!(pub_arg.msg, b"hello");
+            }
+            _ => panic!(),
+        }
+        let (r, _) = p.parse(&buf[n..]).unwrap();
+        match r {
+            ParseResult::PubArg(pub_arg) => assert_eq!(pub_arg.msg, b"abc"),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_pub_reply() {
+        let mut p = Parser::new();
+        let (r, _) = p.parse(b"PUB subject _INBOX.1 5\r\nhello\r\n").unwrap();
+        assert_eq!(
+            r,
+            ParseResult::PubArg(PubArg {
+                subject: "subject",
+                reply_to: Some("_INBOX.1"),
+                hdr_len: None,
+                size_buf: "5",
+                size: 5,
+                msg: b"hello",
+            })
+        );
+        assert!(p.parse(b"PUB a b c 5\r\nhello\r\n").is_err());
+    }
+
+    #[test]
+    fn test_pub_empty() {
+        let mut p = Parser::new();
+        let buf = b"PUB subject _INBOX.1 0\r\n\r\nPUB subject 0\r\n\r\n";
+        let (r, n) = p.parse(buf).unwrap();
+        match r {
+            ParseResult::PubArg(pub_arg) => {
+                assert_eq!(pub_arg.reply_to, Some("_INBOX.1"));
+                assert_eq!(pub_arg.size, 0);
+                assert!(pub_arg.msg.is_empty());
+            }
+            _ => panic!(),
+        }
+        let (r, m) = p.parse(&buf[n..]).unwrap();
+        match r {
+            ParseResult::PubArg(pub_arg) => assert!(pub_arg.msg.is_empty()),
+            _ => panic!(),
+        }
+        assert_eq!(n + m, buf.len());
+    }
+
+    #[test]
+    fn test_hpub() {
+        let mut p = Parser::new();
+        let buf = b"HPUB subject reply 12 17\r\nNATS/1.0\r\n\r\nhello\r\nPUB a 1\r\nx\r\n";
+        let (r, n) = p.parse(buf).unwrap();
+        match r {
+            ParseResult::PubArg(pub_arg) => {
+                assert_eq!(pub_arg.subject, "subject");
+                assert_eq!(pub_arg.reply_to, Some("reply"));
+                assert_eq!(pub_arg.hdr_len, Some(12));
+                assert_eq!(pub_arg.size_buf, "17");
+                assert_eq!(pub_arg.headers(), Some(&b"NATS/1.0\r\n\r\n"[..]));
+                assert_eq!(pub_arg.payload(), b"hello");
+            }
+            _ => panic!(),
+        }
+        match p.parse(&buf[n..]).unwrap().0 {
+            ParseResult::PubArg(pub_arg) => {
+                assert_eq!(pub_arg.hdr_len, None);
+                assert_eq!(pub_arg.payload(), b"x");
+            }
+            _ => panic!(),
+        }
+        let (r, _) = p.parse(b"HPUB subject 2 2\r\nab\r\n").unwrap();
+        assert!(matches!(
+            r,
+            ParseResult::PubArg(PubArg { reply_to: None, .. })
+        ));
+        assert!(p.parse(b"HPUB subject 5 2\r\n").is_err());
+        assert!(p.parse(b"HPUB subject 5\r\n").is_err());
+    }
+
+    #[test]
+    fn test_pub_split_and_large() {
+        let mut p = Parser::new();
+        let (r, n) = p.parse(b"PUB subject 5\r\nhe").unwrap();
+        assert_eq!(r, ParseResult::NoMsg);
+        assert_eq!(n, 17);
+        match p.parse(b"llo\r\n").unwrap().0 {
+            ParseResult::PubArg(pub_arg) => assert_eq!(pub_arg.msg, b"hello"),
+            _ => panic!(),
+        }
+
+        let payload = vec![b'x'; 1000];
+        let mut buf = b"PUB subject 1000\r\n".to_vec();
+        buf.extend_from_slice(&payload);
+        buf.extend_from_slice(b"\r\n");
+        match p.parse(&buf).unwrap().0 {
+            ParseResult::PubArg(pub_arg) => assert_eq!(pub_arg.msg, payload.as_slice()),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut p = Parser::with_limits(16, 4);
+        assert!(p.parse(b"PUB a 4\r\nabcd\r\n").is_ok());
+        let e = p.parse(b"PUB a 5\r\nabcde\r\n").unwrap_err();
+        assert_eq!(e.err_code(), ERROR_MESSAGE_SIZE_TOO_LARGE);
+        let mut p = Parser::with_limits(16, 4);
+        let e = p.parse(b"SUB a.very.long.subject 1\r\n").unwrap_err();
+        assert_eq!(e.err_code(), ERROR_MAX_CONTROL_LINE);
     }
 }
@@ -0,0 +1,177 @@
+use crate::simple_sublist::ArcSubscription;
+use rand::Rng;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::Mutex;
+
+/**
+queue group中的每条消息只投递给其中一个订阅者.
+QueueSelector负责决定从哪个订阅者开始尝试投递,
+如果这个订阅者因为max_msgs等原因不能投递,就依次尝试后面的订阅者.
+*/
+pub trait QueueSelector: Debug + Send + Sync {
+    /**
+     * 返回开始尝试的下标,qsubs是account中同一个queue group匹配到的订阅,不会为空
+     */
+    fn select(&self, account: &str, qsubs: &[ArcSubscription]) -> usize;
+
+    /**
+     * account中订阅subject的queue group已经没有成员,清理为它保存的状态
+     */
+    fn remove(&self, _account: &str, _subject: &str, _queue: &str) {}
+}
+
+/**
+ * 服务端配置使用的选择策略
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QueueStrategy {
+    #[default]
+    Random,
+    RoundRobin,
+    LeastRecentlyDelivered,
+}
+
+impl QueueStrategy {
+    pub fn selector(&self) -> Box<dyn QueueSelector> {
+        match self {
+            QueueStrategy::Random => Box::<RandomSelector>::default(),
+            QueueStrategy::RoundRobin => Box::<RoundRobinSelector>::default(),
+            QueueStrategy::LeastRecentlyDelivered => {
+                Box::<LeastRecentlyDeliveredSelector>::default()
+            }
+        }
+    }
+}
+
+// 随机选择
+#[derive(Debug, Default)]
+pub struct RandomSelector;
+
+impl QueueSelector for RandomSelector {
+    fn select(&self, _account: &str, qsubs: &[ArcSubscription]) -> usize {
+        rand::thread_rng().gen_range(0..qsubs.len())
+    }
+}
+
+// 每个queue group轮流选择,(account, subject, queue) -> 下一次选择的计数
+// 不同的主题匹配到同一个queue group时,按照第一个订阅者的主题计数
+type RoundRobinKey = (String, String, String);
+
+#[derive(Debug, Default)]
+pub struct RoundRobinSelector {
+    next: Mutex<HashMap<RoundRobinKey, usize>>,
+}
+
+impl QueueSelector for RoundRobinSelector {
+    fn select(&self, account: &str, qsubs: &[ArcSubscription]) -> usize {
+        let sub = &qsubs[0];
+        let key = (
+            account.to_string(),
+            sub.subject.clone(),
+            sub.queue.clone().unwrap_or_default(),
+        );
+        let mut next = self.next.lock().unwrap();
+        let n = next.entry(key).or_default();
+        let index = *n % qsubs.len();
+        *n = n.wrapping_add(1);
+        index
+    }
+
+    fn remove(&self, account: &str, subject: &str, queue: &str) {
+        let key = (account.to_string(), subject.to_string(), queue.to_string());
+        self.next.lock().unwrap().remove(&key);
+    }
+}
+
+// 选择最久没有收到消息的订阅者
+#[derive(Debug, Default)]
+pub struct LeastRecentlyDeliveredSelector;
+
+impl QueueSelector for LeastRecentlyDeliveredSelector {
+    fn select(&self, _account: &str, qsubs: &[ArcSubscription]) -> usize {
+        qsubs
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, sub)| sub.last_delivery())
+            .map(|(i, _)| i)
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ClientMessageSender;
+    use crate::simple_sublist::SubScription;
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+
+    fn new_group(n: usize) -> Vec<ArcSubscription> {
+        new_subject_group("jobs", n)
+    }
+
+    fn new_subject_group(subject: &str, n: usize) -> Vec<ArcSubscription> {
+        (0..n)
+            .map(|i| {
+                let sender = Arc::new(Mutex::new(ClientMessageSender::new(tokio::io::sink())));
+                Arc::new(SubScription::new(
+                    sender,
+                    subject,
+                    Some("workers"),
+                    &i.to_string(),
+                ))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_random() {
+        let group = new_group(3);
+        let selector = QueueStrategy::Random.selector();
+        for _ in 0..100 {
+            assert!(selector.select("$G", &group) < 3);
+        }
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let group = new_group(3);
+        let selector = QueueStrategy::RoundRobin.selector();
+        let picks: Vec<usize> = (0..6).map(|_| selector.select("$G", &group)).collect();
+        assert_eq!(picks, vec![0, 1, 2, 0, 1, 2]);
+
+        // 不同主题和不同账户中同名的queue group各自计数
+        let other = new_subject_group("other", 3);
+        assert_eq!(selector.select("$G", &other), 0);
+        assert_eq!(selector.select("A", &group), 0);
+        assert_eq!(selector.select("$G", &group), 0);
+        // queue group没有成员后重新开始计数
+        selector.select("$G", &group);
+        selector.remove("$G", "jobs", "workers");
+        assert_eq!(selector.select("$G", &group), 0);
+    }
+
+    #[test]
+    fn test_least_recently_delivered() {
+        let group = new_group(3);
+        let selector = QueueStrategy::LeastRecentlyDelivered.selector();
+        group[1].acquire_delivery();
+        group[0].acquire_delivery();
+        assert_eq!(selector.select("$G", &group), 2);
+        group[2].acquire_delivery();
+        assert_eq!(selector.select("$G", &group), 1);
+        // 一直按照最久没有投递的选择,每个订阅者轮流收到
+        let mut picks: Vec<usize> = (0..3)
+            .map(|_| {
+                let i = selector.select("$G", &group);
+                group[i].acquire_delivery();
+                i
+            })
+            .collect();
+        picks.sort();
+        assert_eq!(picks, vec![0, 1, 2]);
+    }
+}
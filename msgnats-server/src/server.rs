@@ -1,13 +1,35 @@
-use std::{collections::HashMap, error::Error, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use log::{debug, error, info, warn};
 
 use tokio::{
+    io::AsyncWriteExt,
     net::{TcpListener, TcpStream},
-    sync::Mutex,
+    sync::{watch, Mutex},
+    task::JoinHandle,
 };
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
 
 use crate::{
-    client::{Client, ClientMessageSender},
-    simple_sublist::SubListTrait,
+    account::{map_subject, Account, Interest, Route, GLOBAL_ACCOUNT},
+    client::{Client, ClientEvent, ClientMessageSender},
+    config::ServerConfig,
+    errors::{NError, Result as NResult, ERROR_MAX_CONNECTIONS},
+    gateway::{self, InboundGateway, OutboundGateway},
+    info::ServerInfo,
+    jetstream::JetStream,
+    leaf::{self, LeafEntry},
+    queue::QueueSelector,
+    route::{self, RouteEntry},
+    simple_sublist::{ArcSubscription, ConnKind, SubListTrait},
+    tls,
+    trie_sublist::subject_matches,
 };
 
 /**
@@ -18,11 +40,441 @@ pub struct Server<T: SubListTrait> {
     state: Arc<Mutex<ServerState<T>>>,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct ServerState<T: SubListTrait> {
     pub clients: HashMap<u64, Arc<Mutex<ClientMessageSender>>>, // 服务端维护的客户端集合
-    pub sub_list: T,                                            // 订阅管理列表
+    pub accounts: HashMap<String, Account<T>>,                  // 账户,每个账户有自己的订阅列表
     pub gen_cid: u64,                                           // 服务端维护全局客户端ID
+    pub pending_clients: usize, // 正在进行TLS握手的客户端,同样占用max_connections
+    pub stopped: bool,          // 已经停止接受新的连接,握手完成的客户端不再加入
+    pub config: Arc<ServerConfig>, // 服务端配置
+    pub info: ServerInfo,       // 连接建立时发送给客户端的INFO
+    pub queue_selector: Arc<dyn QueueSelector>, // queue group选择订阅者的策略
+    pub stats: ServerStats,     // 服务端统计
+    pub routes: HashMap<String, RouteEntry>, // 集群中已经建立的路由,对端server_id -> 路由
+    pub client_urls: Vec<String>, // 本服务端对外公布的客户端地址
+    pub route_url: String,      // 本服务端对外公布的路由地址,没有配置集群时为空
+    pub leafs: HashMap<u64, LeafEntry>, // 已经建立的叶子节点连接
+    pub gateways: HashMap<String, OutboundGateway>, // 到其他集群的outbound,集群名字 -> 连接
+    pub inbound_gateways: HashMap<u64, InboundGateway>, // 其他集群连接过来的inbound
+    pub gateway_solicits: HashSet<String>, // 已经在主动连接的集群名字
+    pub gateway_url: String,    // 本服务端对外公布的网关地址,没有配置网关时为空
+}
+
+/**
+ * 各种限制触发的次数
+ */
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ServerStats {
+    pub max_connections_exceeded: u64,
+    pub max_subscriptions_exceeded: u64, // 单个客户端的订阅数量超过max_subscriptions
+    pub account_max_subscriptions_exceeded: u64, // 账户的订阅总数超过账户的max_subscriptions
+}
+
+impl<T: SubListTrait> ServerState<T> {
+    // 配置中的账户在启动时创建,其他服务端只能使用这些账户
+    pub fn new(sub_list: T, config: ServerConfig) -> Self
+    where
+        T: Default,
+    {
+        let info = ServerInfo {
+            max_payload: config.max_payload,
+            auth_required: config.auth_required(),
+            tls_required: config.tls.is_some(),
+            tls_verify: config.tls.as_ref().is_some_and(|tls| tls.verify_client()),
+            cluster: config
+                .cluster
+                .as_ref()
+                .map(|c| c.name.clone())
+                .unwrap_or_default(),
+            ..Default::default()
+        };
+        let mut accounts = HashMap::from([(
+            GLOBAL_ACCOUNT.to_string(),
+            Account::new(GLOBAL_ACCOUNT, sub_list),
+        )]);
+        for name in config.accounts.keys() {
+            accounts
+                .entry(name.clone())
+                .or_insert_with(|| Account::new(name, T::default()));
+        }
+        Self {
+            clients: HashMap::new(),
+            accounts,
+            gen_cid: 0,
+            pending_clients: 0,
+            stopped: false,
+            queue_selector: config.queue_strategy.selector().into(),
+            config: Arc::new(config),
+            info,
+            stats: ServerStats::default(),
+            routes: HashMap::new(),
+            client_urls: Vec::new(),
+            route_url: String::new(),
+            leafs: HashMap::new(),
+            gateways: HashMap::new(),
+            inbound_gateways: HashMap::new(),
+            gateway_solicits: HashSet::new(),
+            gateway_url: String::new(),
+        }
+    }
+
+    /**
+     * 发送给网关的INFO,带上本集群的名字和本服务端的网关地址
+     */
+    pub fn gateway_info(&self) -> ServerInfo {
+        let name = self.config.gateway.as_ref().map(|g| g.name.clone());
+        ServerInfo {
+            gateway: name.unwrap_or_default(),
+            gateway_url: self.gateway_url.clone(),
+            ..self.info.clone()
+        }
+    }
+
+    /**
+     * 发送给路由的INFO,带上本服务端的路由地址和客户端地址
+     */
+    pub fn route_info(&self) -> ServerInfo {
+        ServerInfo {
+            connect_urls: self.client_urls.clone(),
+            ip: self.route_url.clone(),
+            auth_required: (self.config.cluster.as_ref())
+                .is_some_and(|c| c.authorization.is_required()),
+            ..self.info.clone()
+        }
+    }
+
+    /**
+     * 路由变化后重新计算集群中所有服务端的客户端地址,有变化时返回需要通知所有客户端的INFO
+     * 调用者释放state锁之后再发送
+     */
+    pub fn update_connect_urls(&mut self) -> Option<Broadcast> {
+        let mut peers: Vec<&String> = self
+            .routes
+            .values()
+            .flat_map(|r| r.connect_urls.iter())
+            .filter(|url| !self.client_urls.contains(url))
+            .collect();
+        peers.sort();
+        peers.dedup();
+        let mut connect_urls = self.client_urls.clone();
+        connect_urls.extend(peers.into_iter().cloned());
+        if connect_urls == self.info.connect_urls {
+            return None;
+        }
+        self.info.connect_urls = connect_urls;
+        Some(Broadcast {
+            senders: self.clients.values().cloned().collect(),
+            data: self.info.to_protocol(),
+        })
+    }
+}
+
+/**
+ * 持有state锁时收集的需要发送给多个连接的数据,释放锁之后再发送,慢连接不会阻塞其他连接
+ */
+#[derive(Debug)]
+pub struct Broadcast {
+    senders: Vec<Arc<Mutex<ClientMessageSender>>>,
+    data: Vec<u8>,
+}
+
+impl Broadcast {
+    pub async fn send(self) {
+        for sender in self.senders {
+            let _ = sender.lock().await.send_raw(&self.data).await;
+        }
+    }
+}
+
+/**
+ * 监听在0.0.0.0或者::时使用本机所有非回环网卡的地址,这样其他服务端和客户端才能连接
+ */
+fn advertise_addrs(addr: SocketAddr) -> Vec<SocketAddr> {
+    if !addr.ip().is_unspecified() {
+        return vec![addr];
+    }
+    let interfaces = get_if_addrs::get_if_addrs().unwrap_or_default();
+    let mut addrs: Vec<SocketAddr> = interfaces
+        .iter()
+        .map(|i| i.ip())
+        .filter(|ip| match ip {
+            IpAddr::V4(ip) => !ip.is_loopback(),
+            // 链路本地地址需要指定网卡,不能给其他机器使用
+            IpAddr::V6(ip) => {
+                addr.is_ipv6() && !ip.is_loopback() && ip.segments()[0] & 0xffc0 != 0xfe80
+            }
+        })
+        .map(|ip| SocketAddr::new(ip, addr.port()))
+        .collect();
+    if addrs.is_empty() {
+        addrs.push(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), addr.port()));
+    }
+    addrs
+}
+
+impl<T: SubListTrait + Default> ServerState<T> {
+    // 没有配置的账户在第一个客户端加入时创建
+    pub fn account_mut(&mut self, name: &str) -> &mut Account<T> {
+        self.accounts
+            .entry(name.to_string())
+            .or_insert_with(|| Account::new(name, T::default()))
+    }
+
+    /**
+     * 查找发布到account中subject的消息需要投递的订阅
+     * 除了本账户的订阅,还包括服务请求的响应、本账户导入的服务以及导入了本账户stream的其他账户
+     */
+    pub fn route(
+        &mut self,
+        account: &str,
+        subject: &str,
+        reply_to: Option<&str>,
+    ) -> NResult<Vec<Route>> {
+        let config = self.config.clone();
+        let mut routes = vec![Route {
+            account: account.to_string(),
+            subject: subject.to_string(),
+            reply_to: reply_to.map(|r| r.to_string()),
+            result: self.account_mut(account).sub_list.match_subject(subject)?,
+            gateways: Vec::new(),
+        }];
+        // 导出服务的响应,投递回请求方所在账户原来的reply_to,只投递一次
+        let acc = self.account_mut(account);
+        acc.prune_responses();
+        if let Some(response) = acc.responses.remove(subject) {
+            let result = self
+                .account_mut(&response.account)
+                .sub_list
+                .match_subject(&response.reply_to)?;
+            routes.push(Route {
+                account: response.account,
+                subject: response.reply_to,
+                reply_to: None,
+                result,
+                gateways: Vec::new(),
+            });
+        }
+        // 本账户导入的服务,请求转发给导出服务的账户
+        let imports = config.accounts.get(account).map(|a| a.imports.as_slice());
+        for import in imports.unwrap_or_default() {
+            let source = match import.service {
+                Some(ref source) => source,
+                None => continue,
+            };
+            let local_subject = import.local_subject(source);
+            let exported = config
+                .accounts
+                .get(&source.account)
+                .is_some_and(|a| a.exports(&source.subject, account, true));
+            if !exported || !subject_matches(&local_subject, subject) {
+                continue;
+            }
+            let subject = map_subject(&local_subject, &source.subject, subject);
+            let result = self
+                .account_mut(&source.account)
+                .sub_list
+                .match_subject(&subject)?;
+            // 没有订阅者时请求不会被处理,不需要记录响应映射
+            if result.is_empty()
+                && self
+                    .optimistic_gateways(&source.account, &subject)
+                    .is_empty()
+            {
+                continue;
+            }
+            let exporter = self.account_mut(&source.account);
+            let reply_to = reply_to.map(|r| exporter.add_response(account, r));
+            routes.push(Route {
+                account: source.account.clone(),
+                result,
+                subject,
+                reply_to,
+                gateways: Vec::new(),
+            });
+        }
+        // 导入了本账户stream的其他账户,stream不支持回复
+        let exports = config.accounts.get(account);
+        for (importer, importer_config) in config.accounts.iter() {
+            for import in importer_config.imports.iter() {
+                let source = match import.stream {
+                    Some(ref source) if source.account == account => source,
+                    _ => continue,
+                };
+                let exported = exports.is_some_and(|a| a.exports(&source.subject, importer, false));
+                if !exported || !subject_matches(&source.subject, subject) {
+                    continue;
+                }
+                let subject = map_subject(&source.subject, &import.local_subject(source), subject);
+                routes.push(Route {
+                    account: importer.clone(),
+                    result: self
+                        .account_mut(importer)
+                        .sub_list
+                        .match_subject(&subject)?,
+                    subject,
+                    reply_to: None,
+                    gateways: Vec::new(),
+                });
+            }
+        }
+        // 乐观模式的网关没有订阅也需要发送
+        for route in routes.iter_mut() {
+            route.gateways = self.optimistic_gateways(&route.account, &route.subject);
+        }
+        Ok(routes)
+    }
+
+    // 乐观模式下需要发送这个主题的网关
+    pub fn optimistic_gateways(
+        &self,
+        account: &str,
+        subject: &str,
+    ) -> Vec<Arc<Mutex<ClientMessageSender>>> {
+        self.gateways
+            .values()
+            .filter(|g| g.optimistic(account, subject))
+            .map(|g| g.sender.clone())
+            .collect()
+    }
+
+    /**
+     * 加入订阅,第一次订阅某个主题时通过RS+通知所有路由,通过LS+通知账户的叶子节点
+     * 路由的订阅不会再传播给其他路由,网关的订阅只在本服务端使用
+     */
+    pub fn insert_sub(&mut self, account: &str, sub: ArcSubscription) -> NResult<()> {
+        let acc = self.account_mut(account);
+        acc.sub_list.insert(sub.clone())?;
+        if sub.kind == ConnKind::Client {
+            acc.client_subs += 1;
+        }
+        if sub.queue.is_some() {
+            let interest = (sub.subject.clone(), sub.queue.clone());
+            *acc.queue_subs.entry(interest).or_default() += 1;
+        }
+        if sub.kind == ConnKind::Gateway {
+            return Ok(());
+        }
+        let interest = (sub.subject.clone(), sub.queue.clone());
+        let count = acc.leaf_interest.entry(interest.clone()).or_default();
+        *count += 1;
+        let others = *count - 1;
+        if matches!(sub.kind, ConnKind::Client | ConnKind::Leaf) {
+            let count = acc.interest.entry(interest).or_default();
+            *count += 1;
+            if *count == 1 {
+                let data =
+                    route::interest_protocol(true, account, &sub.subject, sub.queue.as_deref());
+                self.send_routes(&data);
+            }
+        }
+        self.send_leafs(true, account, &sub, others);
+        self.send_gateways(true, account, &sub, others);
+        Ok(())
+    }
+
+    /**
+     * 删除订阅,不再有订阅某个主题时通过RS-/LS-通知路由、叶子节点和网关
+     */
+    pub fn remove_sub(&mut self, account: &str, sub: ArcSubscription) -> NResult<()> {
+        let acc = self.account_mut(account);
+        acc.sub_list.remove(sub.clone())?;
+        if sub.kind == ConnKind::Client {
+            acc.client_subs -= 1;
+        }
+        let interest = (sub.subject.clone(), sub.queue.clone());
+        // queue group的最后一个成员退出后清理选择器为它保存的状态
+        if let Some(ref queue) = sub.queue {
+            if release(&mut acc.queue_subs, &interest) == Some(0) {
+                self.queue_selector.remove(account, &sub.subject, queue);
+            }
+        }
+        if sub.kind == ConnKind::Gateway {
+            return Ok(());
+        }
+        let acc = self.account_mut(account);
+        let others = release(&mut acc.leaf_interest, &interest);
+        if matches!(sub.kind, ConnKind::Client | ConnKind::Leaf)
+            && release(&mut acc.interest, &interest) == Some(0)
+        {
+            let data = route::interest_protocol(false, account, &sub.subject, sub.queue.as_deref());
+            self.send_routes(&data);
+        }
+        if let Some(others) = others {
+            self.send_leafs(false, account, &sub, others);
+            self.send_gateways(false, account, &sub, others);
+        }
+        Ok(())
+    }
+
+    // 交给所有路由的任务发送,持有server锁时不直接写连接,写失败由路由自己的任务负责清理
+    fn send_routes(&mut self, data: &[u8]) {
+        for route in self.routes.values() {
+            let _ = route.events.send(ClientEvent::Send(data.to_vec()));
+        }
+    }
+
+    // 通知账户的叶子节点,订阅来自的叶子节点除外
+    // others是其他订阅的数量,等于叶子节点自己的订阅数量时说明对这个叶子节点的兴趣发生了变化
+    fn send_leafs(&mut self, add: bool, account: &str, sub: &ArcSubscription, others: usize) {
+        let interest = (sub.subject.clone(), sub.queue.clone());
+        let data = leaf::interest_protocol(add, &sub.subject, sub.queue.as_deref());
+        for leaf in self.leafs.values() {
+            if leaf.account != account || Arc::ptr_eq(&leaf.sender, &sub.msg_sender) {
+                continue;
+            }
+            if others == leaf.interest.contains(&interest) as usize {
+                let _ = leaf.events.send(ClientEvent::Send(data.clone()));
+            }
+        }
+    }
+}
+
+impl<T: SubListTrait> ServerState<T> {
+    /**
+     * 通知其他集群的inbound:queue group以及interest-only模式的账户在订阅数量变化时发送RS+/RS-,
+     * 乐观模式下只需要取消之前回复过的、和新订阅匹配的RS-
+     */
+    fn send_gateways(&mut self, add: bool, account: &str, sub: &ArcSubscription, others: usize) {
+        for gateway in self.inbound_gateways.values_mut() {
+            let inbound = gateway.accounts.entry(account.to_string()).or_default();
+            let mut data = Vec::new();
+            if sub.queue.is_some() || inbound.interest_only {
+                if others == 0 {
+                    let queue = sub.queue.as_deref();
+                    data = route::interest_protocol(add, account, &sub.subject, queue);
+                }
+            } else if add {
+                inbound.no_interest.retain(|subject| {
+                    let matched = subject_matches(&sub.subject, subject);
+                    if matched {
+                        data.extend(route::interest_protocol(true, account, subject, None));
+                    }
+                    !matched
+                });
+            }
+            if !data.is_empty() {
+                let _ = gateway.events.send(ClientEvent::Send(data));
+            }
+        }
+    }
+}
+
+// 订阅数量减一,返回剩余的数量,为0时删除
+fn release(counts: &mut HashMap<Interest, usize>, interest: &Interest) -> Option<usize> {
+    let count = counts.get_mut(interest)?;
+    *count -= 1;
+    let count = *count;
+    if count == 0 {
+        counts.remove(interest);
+    }
+    Some(count)
+}
+
+impl<T: SubListTrait + Default> Default for ServerState<T> {
+    fn default() -> Self {
+        Self::new(T::default(), ServerConfig::default())
+    }
 }
 
 /**
@@ -30,41 +482,1415 @@ pub struct ServerState<T: SubListTrait> {
  * send 多线程特征 static 静态生命周期特性
  *
  */
-impl<T: SubListTrait + Send + 'static> Server<T> {
-    // 服务端启动方法
-    pub async fn start(self) -> Result<(), Box<dyn Error>> {
-        let addr = "127.0.0.1:18888";
-        let listener = TcpListener::bind(addr).await?;
+impl<T: SubListTrait + Default + Send + 'static> Server<T> {
+    pub fn new(sub_list: T, config: ServerConfig) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(ServerState::new(sub_list, config))),
+        }
+    }
+
+    // 服务端启动方法,返回的ServerHandle用于停止服务
+    pub async fn start(self) -> Result<ServerHandle<T>, Box<dyn Error>> {
+        let config = self.state.lock().await.config.clone();
+        // 不是从配置文件加载的配置同样需要检查
+        config.validate().map_err(|(_, message)| message)?;
+        let tls_acceptor = match config.tls {
+            Some(ref tls) => Some(tls.acceptor()?),
+            None => None,
+        };
+        let listener = TcpListener::bind(config.addr()).await?;
+        let local_addr = listener.local_addr()?;
+        let cluster_listener = match config.cluster {
+            Some(ref cluster) => Some(TcpListener::bind(cluster.addr()).await?),
+            None => None,
+        };
+        let cluster_addr = match cluster_listener {
+            Some(ref listener) => Some(listener.local_addr()?),
+            None => None,
+        };
+        let gateway_listener = match config.gateway {
+            Some(ref gateway) => Some(TcpListener::bind(gateway.addr()).await?),
+            None => None,
+        };
+        let gateway_addr = match gateway_listener {
+            Some(ref listener) => Some(listener.local_addr()?),
+            None => None,
+        };
+        let leaf_listener = match config.leafnodes.as_ref().and_then(|l| l.addr()) {
+            Some(addr) => Some(TcpListener::bind(addr).await?),
+            None => None,
+        };
+        let leaf_addr = match leaf_listener {
+            Some(ref listener) => Some(listener.local_addr()?),
+            None => None,
+        };
+        {
+            let mut state = self.state.lock().await;
+            state.info.host = local_addr.ip().to_string();
+            state.info.port = local_addr.port();
+            info!("listening for client connections on {}", local_addr);
+            let client_urls = advertise_addrs(local_addr)
+                .into_iter()
+                .map(|a| a.to_string());
+            state.client_urls = client_urls.collect();
+            if let Some(cluster_addr) = cluster_addr {
+                state.route_url = format!("nats-route://{}/", advertise_addrs(cluster_addr)[0]);
+                state.info.connect_urls = state.client_urls.clone();
+            }
+            if let Some(gateway_addr) = gateway_addr {
+                state.gateway_url = format!("nats://{}", advertise_addrs(gateway_addr)[0]);
+            }
+        }
 
-        tokio::spawn(async move {
+        let state = self.state.clone();
+        let (stop_tx, mut stop_rx) = watch::channel(false);
+        let mut tasks = Vec::new();
+        if let (Some(listener), Some(cluster)) = (cluster_listener, config.cluster.as_ref()) {
+            info!(
+                "listening for route connections on {}",
+                listener.local_addr()?
+            );
+            let accept_routes = route::accept_routes(state.clone(), listener, stop_tx.subscribe());
+            tasks.push(tokio::spawn(accept_routes));
+            for url in cluster.routes.iter() {
+                let solicit =
+                    route::solicit_route(state.clone(), url.clone(), stop_tx.subscribe(), false);
+                tasks.push(tokio::spawn(solicit));
+            }
+        }
+        if let Some(listener) = leaf_listener {
+            info!(
+                "listening for leaf node connections on {}",
+                listener.local_addr()?
+            );
+            let accept_leafs = leaf::accept_leafs(state.clone(), listener, stop_tx.subscribe());
+            tasks.push(tokio::spawn(accept_leafs));
+        }
+        if let (Some(listener), Some(gateway)) = (gateway_listener, config.gateway.as_ref()) {
+            info!(
+                "listening for gateway connections on {}",
+                listener.local_addr()?
+            );
+            let accept_gateways =
+                gateway::accept_gateways(state.clone(), listener, stop_tx.subscribe());
+            tasks.push(tokio::spawn(accept_gateways));
+            let remotes = gateway.gateways.iter().filter(|r| r.name != gateway.name);
+            for remote in remotes {
+                state
+                    .lock()
+                    .await
+                    .gateway_solicits
+                    .insert(remote.name.clone());
+                let solicit =
+                    gateway::solicit_gateway(state.clone(), remote.clone(), stop_tx.subscribe());
+                tasks.push(tokio::spawn(solicit));
+            }
+        }
+        if let Some(ref jetstream) = config.jetstream {
+            info!("jetstream store directory {:?}", jetstream.store_dir);
+            let jetstream = JetStream::start(state.clone(), jetstream).await?;
+            tasks.push(tokio::spawn(jetstream.run(stop_tx.subscribe())));
+        }
+        for remote in config.leafnodes.iter().flat_map(|l| l.remotes.iter()) {
+            let solicit = leaf::solicit_leaf(state.clone(), remote.clone(), stop_tx.subscribe());
+            tasks.push(tokio::spawn(solicit));
+        }
+        let accept_task = tokio::spawn(async move {
             loop {
-                let rc = listener.accept().await;
+                let rc = tokio::select! {
+                    rc = listener.accept() => rc,
+                    _ = stop_rx.changed() => return,
+                };
                 if rc.is_err() {
-                    print!("accecpt conn is error:{}", rc.err().unwrap()); // rc.unwrap_err()
+                    error!("accecpt conn is error:{}", rc.err().unwrap()); // rc.unwrap_err()
                     return;
                 }
                 //  let r = rc.ok().unwrap();// rc.unwrap();
                 let (conn, _) = rc.unwrap();
-                self.new_client(conn).await;
+                self.new_client(conn, tls_acceptor.as_ref()).await;
             }
         });
 
-        Ok(())
+        tasks.push(accept_task);
+        Ok(ServerHandle {
+            state,
+            local_addr,
+            cluster_addr,
+            leaf_addr,
+            gateway_addr,
+            stop_tx,
+            tasks,
+        })
     }
     // 客户端创建方法  服务器私有
-    async fn new_client(&self, conn: TcpStream) {
-        let server_state = self.state.clone();
-        let cid = {
-            let mut state = server_state.lock().await;
+    // 所有写操作都不持有state锁,避免慢客户端阻塞其他连接
+    async fn new_client(&self, mut conn: TcpStream, tls_acceptor: Option<&TlsAcceptor>) {
+        // 接受连接的任务中写入都有超时,不读取数据的客户端不会阻塞接受其他连接
+        let (cid, info, write_deadline) = {
+            let mut state = self.state.lock().await;
+            let write_deadline = state.config.write_deadline;
+            let max_connections = state.config.max_connections;
+            let connections = state.clients.len() + state.pending_clients;
+            if max_connections > 0 && connections >= max_connections {
+                warn!("reject connection, maximum connections exceeded");
+                state.stats.max_connections_exceeded += 1;
+                drop(state);
+                let err = NError::new(ERROR_MAX_CONNECTIONS).to_protocol();
+                let _ = tokio::time::timeout(write_deadline, conn.write_all(&err)).await;
+                return;
+            }
             state.gen_cid += 1;
-            state.gen_cid
+            let cid = state.gen_cid;
+            debug!("client {} connected from {:?}", cid, conn.peer_addr());
+            let mut info = state.info.clone();
+            info.client_id = cid;
+            if let Ok(peer_addr) = conn.peer_addr() {
+                info.client_ip = peer_addr.ip().to_string();
+            }
+            // TLS握手完成之前就预留连接数量,避免大量握手中的连接绕过max_connections
+            if tls_acceptor.is_some() {
+                state.pending_clients += 1;
+            }
+            (cid, info, write_deadline)
+        };
+        if let Some(tls_acceptor) = tls_acceptor {
+            tokio::spawn(Self::new_tls_client(
+                self.state.clone(),
+                cid,
+                info,
+                conn,
+                tls_acceptor.clone(),
+            ));
+            return;
+        }
+        // client任务启动前直接写入连接,INFO一定是发给客户端的第一条数据
+        match tokio::time::timeout(write_deadline, conn.write_all(&info.to_protocol())).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                warn!("send info to client {} error:{}", cid, e);
+                return;
+            }
+            Err(_) => {
+                warn!("send info to client {} timeout", cid);
+                return;
+            }
+        }
+        // 持有锁直到client加入集合,避免连接立即断开时清理先于插入执行
+        let mut state = self.state.lock().await;
+        if state.stopped {
+            return;
+        }
+        let config = state.config.clone();
+        let client_message_sender =
+            Client::process_connection(cid, self.state.clone(), config, conn, Vec::new());
+        state.clients.insert(cid, client_message_sender);
+    }
+
+    // 先明文发送INFO,等客户端发起TLS握手,握手在独立的任务中进行,不影响接受其他连接
+    async fn new_tls_client(
+        serv_state: Arc<Mutex<ServerState<T>>>,
+        cid: u64,
+        info: ServerInfo,
+        conn: TcpStream,
+        tls_acceptor: TlsAcceptor,
+    ) {
+        let conn = Self::tls_handshake(&serv_state, cid, &info, conn, tls_acceptor).await;
+        let mut state = serv_state.lock().await;
+        // 不管握手是否成功都释放预留的连接数量
+        state.pending_clients -= 1;
+        let conn = match conn {
+            Some(conn) => conn,
+            None => return,
         };
-        let client_message_sender = Client::process_connection(cid, server_state, conn);
+        // 握手期间服务端已经停止,不再加入,否则关闭服务时不会关闭这个连接
+        if state.stopped {
+            debug!("client {} tls handshake complete after server stopped", cid);
+            return;
+        }
+        let identities = tls::peer_identities(&conn);
+        debug!(
+            "client {} tls handshake complete, identities:{:?}",
+            cid, identities
+        );
+        let config = state.config.clone();
+        let client_message_sender =
+            Client::process_connection(cid, serv_state.clone(), config, conn, identities);
+        state.clients.insert(cid, client_message_sender);
+    }
 
-        self.state
+    async fn tls_handshake(
+        serv_state: &Arc<Mutex<ServerState<T>>>,
+        cid: u64,
+        info: &ServerInfo,
+        mut conn: TcpStream,
+        tls_acceptor: TlsAcceptor,
+    ) -> Option<TlsStream<TcpStream>> {
+        if let Err(e) = conn.write_all(&info.to_protocol()).await {
+            warn!("send info to client {} error:{}", cid, e);
+            return None;
+        }
+        let timeout = serv_state
             .lock()
             .await
-            .clients
-            .insert(cid, client_message_sender);
+            .config
+            .tls
+            .as_ref()
+            .map(|tls| tls.timeout);
+        let handshake = tls_acceptor.accept(conn);
+        match tokio::time::timeout(timeout.unwrap_or_default(), handshake).await {
+            Ok(Ok(conn)) => Some(conn),
+            Ok(Err(e)) => {
+                warn!("client {} tls handshake error:{}", cid, e);
+                None
+            }
+            Err(_) => {
+                warn!("client {} tls handshake timeout", cid);
+                None
+            }
+        }
+    }
+}
+
+// 等待客户端连接关闭的最长时间
+const CLOSE_CLIENTS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/**
+ * 运行中的服务端,用于关闭服务
+ */
+#[derive(Debug)]
+pub struct ServerHandle<T: SubListTrait> {
+    state: Arc<Mutex<ServerState<T>>>,
+    local_addr: SocketAddr,
+    cluster_addr: Option<SocketAddr>,
+    leaf_addr: Option<SocketAddr>,
+    gateway_addr: Option<SocketAddr>,
+    stop_tx: watch::Sender<bool>,
+    tasks: Vec<JoinHandle<()>>, // 接受连接,主动建立路由、叶子节点、网关连接以及jetstream的任务
+}
+
+impl<T: SubListTrait + Default + Send + 'static> ServerHandle<T> {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    // 路由监听的地址,没有配置集群时为None
+    pub fn cluster_addr(&self) -> Option<SocketAddr> {
+        self.cluster_addr
+    }
+
+    // 叶子节点监听的地址,没有配置leafnodes的port时为None
+    pub fn leaf_addr(&self) -> Option<SocketAddr> {
+        self.leaf_addr
+    }
+
+    // 网关监听的地址,没有配置网关时为None
+    pub fn gateway_addr(&self) -> Option<SocketAddr> {
+        self.gateway_addr
+    }
+
+    // 停止接受新的连接以及重连路由,已经建立的连接不受影响
+    async fn stop_accept(&mut self) {
+        let _ = self.stop_tx.send(true);
+        self.state.lock().await.stopped = true;
+        for task in self.tasks.drain(..) {
+            let _ = task.await;
+        }
+    }
+
+    /**
+     * 停止接受新的连接,关闭所有客户端连接以及路由
+     * 客户端会先清理订阅,发送完已经写入的数据后再关闭
+     */
+    pub async fn shutdown(mut self) {
+        info!("server shutting down");
+        self.stop_accept().await;
+        let senders: Vec<_> = {
+            let state = self.state.lock().await;
+            let clients = state.clients.values();
+            let routes = state.routes.values().map(|r| &r.sender);
+            let leafs = state.leafs.values().map(|l| &l.sender);
+            let outbound = state.gateways.values().map(|g| &g.sender);
+            let inbound = state.inbound_gateways.values().map(|g| &g.sender);
+            clients
+                .chain(routes)
+                .chain(leafs)
+                .chain(outbound)
+                .chain(inbound)
+                .cloned()
+                .collect()
+        };
+        // 释放state锁之后再通知,正在写入的慢连接不会阻塞关闭以及其他连接
+        for sender in senders.iter() {
+            sender.lock().await.notify_close();
+        }
+        self.wait_clients_closed().await;
+        info!("server shutdown complete");
+    }
+
+    /**
+     * lame duck mode,停止接受新的连接并且通过INFO告知客户端,
+     * 等待lame_duck_grace_period之后,在剩余的lame_duck_duration时间内逐个关闭客户端连接,
+     * 让客户端有时间重连到其他服务端
+     */
+    pub async fn lame_duck(mut self) {
+        self.stop_accept().await;
+        let (clients, info, duration, grace_period) = {
+            let mut state = self.state.lock().await;
+            state.info.ldm = true;
+            // 按照连接建立的先后顺序关闭
+            let mut clients: Vec<_> = state.clients.iter().collect();
+            clients.sort_by_key(|(cid, _)| **cid);
+            let clients: Vec<_> = clients.into_iter().map(|(_, s)| s.clone()).collect();
+            (
+                clients,
+                state.info.to_protocol(),
+                state.config.lame_duck_duration,
+                state.config.lame_duck_grace_period,
+            )
+        };
+        // 释放state锁之后再发送INFO,慢客户端不会阻塞其他连接
+        for sender in clients.iter() {
+            let _ = sender.lock().await.send_raw(&info).await;
+        }
+        info!(
+            "entering lame duck mode, closing {} clients in {:?}",
+            clients.len(),
+            duration
+        );
+        tokio::time::sleep(grace_period.min(duration)).await;
+        let interval = duration.saturating_sub(grace_period) / clients.len().max(1) as u32;
+        for sender in clients.iter() {
+            sender.lock().await.notify_close();
+            tokio::time::sleep(interval).await;
+        }
+        self.shutdown().await;
+    }
+
+    async fn wait_clients_closed(&self) {
+        let deadline = tokio::time::Instant::now() + CLOSE_CLIENTS_TIMEOUT;
+        while tokio::time::Instant::now() < deadline {
+            let state = self.state.lock().await;
+            if state.clients.is_empty()
+                && state.routes.is_empty()
+                && state.leafs.is_empty()
+                && state.gateways.is_empty()
+                && state.inbound_gateways.is_empty()
+            {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        warn!("timeout waiting for clients to close");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::route::ClusterConfig;
+    use crate::trie_sublist::TrieSubList;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+
+    #[tokio::test]
+    async fn test_new_client_info() {
+        let server: Server<TrieSubList> = Server::default();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let conn = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server_conn, _) = listener.accept().await.unwrap();
+        server.new_client(server_conn, None).await;
+
+        let mut line = String::new();
+        BufReader::new(conn).read_line(&mut line).await.unwrap();
+        assert!(line.starts_with("INFO ") && line.ends_with("\r\n"));
+        let info: ServerInfo = serde_json::from_str(&line[5..line.len() - 2]).unwrap();
+        assert_eq!(info.client_id, 1);
+        assert_eq!(info.client_ip, "127.0.0.1");
+        assert_eq!(info.server_id, server.state.lock().await.info.server_id);
+    }
+
+    #[tokio::test]
+    async fn test_start_invalid_config() {
+        let config = ServerConfig {
+            port: 0,
+            ping_interval: Duration::ZERO,
+            ..Default::default()
+        };
+        match Server::new(TrieSubList::default(), config).start().await {
+            Ok(_) => panic!("zero ping_interval should be rejected"),
+            Err(e) => assert_eq!(
+                e.to_string(),
+                "invalid ping_interval: must be greater than 0"
+            ),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_max_connections() {
+        let config = ServerConfig {
+            max_connections: 1,
+            ..Default::default()
+        };
+        let server = Server::new(TrieSubList::default(), config);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut conns = Vec::new();
+        for _ in 0..2 {
+            let conn = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (server_conn, _) = listener.accept().await.unwrap();
+            server.new_client(server_conn, None).await;
+            conns.push(BufReader::new(conn));
+        }
+        let mut line = String::new();
+        conns[1].read_line(&mut line).await.unwrap();
+        assert_eq!(line, "-ERR 'maximum connections exceeded'\r\n");
+        let s = server.state.lock().await;
+        assert_eq!(s.clients.len(), 1);
+        assert_eq!(s.stats.max_connections_exceeded, 1);
+    }
+
+    async fn start_server(
+        config: ServerConfig,
+    ) -> (ServerHandle<TrieSubList>, Vec<BufReader<TcpStream>>) {
+        let config = ServerConfig { port: 0, ..config };
+        let handle = Server::new(TrieSubList::default(), config)
+            .start()
+            .await
+            .unwrap();
+        let mut conns = Vec::new();
+        for _ in 0..2 {
+            let mut conn = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+            let mut line = String::new();
+            conn.read_line(&mut line).await.unwrap();
+            assert!(line.starts_with("INFO "));
+            conn.get_mut()
+                .write_all(b"SUB foo 1\r\nPING\r\n")
+                .await
+                .unwrap();
+            line.clear();
+            conn.read_line(&mut line).await.unwrap();
+            assert_eq!(line, "PONG\r\n");
+            conns.push(conn);
+        }
+        (handle, conns)
+    }
+
+    async fn assert_closed(conn: &mut BufReader<TcpStream>) {
+        let mut rest = String::new();
+        tokio::time::timeout(Duration::from_secs(5), conn.read_line(&mut rest))
+            .await
+            .expect("connection not closed")
+            .unwrap();
+        assert_eq!(rest, "");
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let (handle, mut conns) = start_server(ServerConfig::default()).await;
+        let addr = handle.local_addr();
+        let state = handle.state.clone();
+        handle.shutdown().await;
+        for conn in conns.iter_mut() {
+            assert_closed(conn).await;
+        }
+        let s = state.lock().await;
+        assert!(s.clients.is_empty());
+        assert_eq!(s.accounts[GLOBAL_ACCOUNT].sub_list.count(), 0);
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_lame_duck() {
+        let config = ServerConfig {
+            lame_duck_duration: Duration::from_millis(300),
+            lame_duck_grace_period: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let (handle, mut conns) = start_server(config).await;
+        let addr = handle.local_addr();
+        let state = handle.state.clone();
+        let lame_duck = tokio::spawn(handle.lame_duck());
+
+        for conn in conns.iter_mut() {
+            let mut line = String::new();
+            conn.read_line(&mut line).await.unwrap();
+            let info: ServerInfo = serde_json::from_str(&line[5..line.len() - 2]).unwrap();
+            assert!(info.ldm);
+        }
+        let start = tokio::time::Instant::now();
+        assert_closed(&mut conns[0]).await;
+        // 第一个连接关闭时第二个连接仍然可以使用
+        conns[1].get_mut().write_all(b"PING\r\n").await.unwrap();
+        let mut line = String::new();
+        conns[1].read_line(&mut line).await.unwrap();
+        assert_eq!(line, "PONG\r\n");
+        assert_closed(&mut conns[1]).await;
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        lame_duck.await.unwrap();
+        assert!(state.lock().await.clients.is_empty());
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    // 生成CA、由CA签发的服务端证书和客户端证书,写入临时目录
+    // name用来区分不同测试的目录,测试并行执行时不会互相覆盖
+    fn generate_certs(name: &str) -> (std::path::PathBuf, rcgen::CertifiedKey) {
+        use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair, SanType};
+        let dir = std::env::temp_dir().join(format!("msgnats-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let ca_key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::default();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "msgnats test ca");
+        let ca = params.self_signed(&ca_key).unwrap();
+        let key = KeyPair::generate().unwrap();
+        let params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+        let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+        std::fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+        std::fs::write(dir.join("server.pem"), cert.pem()).unwrap();
+        std::fs::write(dir.join("server-key.pem"), key.serialize_pem()).unwrap();
+
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::default();
+        params.distinguished_name.push(DnType::CommonName, "alice");
+        params.subject_alt_names = vec![SanType::Rfc822Name(
+            "alice@example.com".to_string().try_into().unwrap(),
+        )];
+        let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+        (
+            dir,
+            rcgen::CertifiedKey {
+                cert,
+                key_pair: key,
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn test_tls() {
+        use tokio::io::AsyncReadExt;
+        use tokio_rustls::rustls::{self, pki_types::ServerName, RootCertStore};
+        let (dir, client) = generate_certs("tls");
+        let config: ServerConfig = serde_json::from_value(serde_json::json!({
+            "port": 0,
+            "tls": {
+                "cert_file": dir.join("server.pem"),
+                "key_file": dir.join("server-key.pem"),
+                "ca_file": dir.join("ca.pem"),
+                "verify_and_map": true
+            },
+            "authorization": {"users": [{"user": "alice@example.com"}]}
+        }))
+        .unwrap();
+        let handle = Server::new(TrieSubList::default(), config)
+            .start()
+            .await
+            .unwrap();
+
+        let mut roots = RootCertStore::empty();
+        let ca = std::fs::read(dir.join("ca.pem")).unwrap();
+        for cert in rustls_pemfile::certs(&mut ca.as_slice()) {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let builder = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots);
+        let key = rustls_pemfile::private_key(&mut client.key_pair.serialize_pem().as_bytes())
+            .unwrap()
+            .unwrap();
+        let with_cert = builder
+            .clone()
+            .with_client_auth_cert(vec![client.cert.der().clone()], key)
+            .unwrap();
+        let without_cert = builder.with_no_client_auth();
+        let server_name = ServerName::try_from("localhost").unwrap();
+
+        // 先收到明文的INFO,再进行TLS握手,证书中的email映射成用户
+        let mut conn = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+        let mut line = String::new();
+        conn.read_line(&mut line).await.unwrap();
+        let info: ServerInfo = serde_json::from_str(&line[5..line.len() - 2]).unwrap();
+        assert!(info.tls_required && info.tls_verify && info.auth_required);
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(with_cert));
+        let conn = connector
+            .connect(server_name.clone(), conn.into_inner())
+            .await
+            .unwrap();
+        let mut conn = BufReader::new(conn);
+        conn.get_mut()
+            .write_all(b"CONNECT {}\r\nPING\r\n")
+            .await
+            .unwrap();
+        line.clear();
+        conn.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "PONG\r\n");
+
+        // 没有客户端证书时握手失败,连接被关闭
+        let mut conn = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+        line.clear();
+        conn.read_line(&mut line).await.unwrap();
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(without_cert));
+        let rejected = async {
+            let mut conn = connector.connect(server_name, conn.into_inner()).await?;
+            conn.write_all(b"CONNECT {}\r\nPING\r\n").await?;
+            conn.read(&mut [0u8; 64]).await
+        };
+        assert!(!matches!(rejected.await, Ok(n) if n > 0));
+
+        handle.shutdown().await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tls_pending_handshake() {
+        use tokio_rustls::rustls::{self, pki_types::ServerName, RootCertStore};
+        let (dir, _) = generate_certs("tls-pending");
+        let config: ServerConfig = serde_json::from_value(serde_json::json!({
+            "port": 0,
+            "max_connections": 1,
+            "tls": {
+                "cert_file": dir.join("server.pem"),
+                "key_file": dir.join("server-key.pem"),
+            }
+        }))
+        .unwrap();
+        let handle = Server::new(TrieSubList::default(), config)
+            .start()
+            .await
+            .unwrap();
+        let state = handle.state.clone();
+
+        // 握手中的连接占用max_connections
+        let mut pending = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+        let mut line = String::new();
+        pending.read_line(&mut line).await.unwrap();
+        assert!(line.starts_with("INFO "));
+        let mut rejected = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+        line.clear();
+        rejected.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "-ERR 'maximum connections exceeded'\r\n");
+        assert_eq!(state.lock().await.pending_clients, 1);
+
+        // 服务端停止之后才完成握手的连接不会加入
+        handle.shutdown().await;
+        let mut roots = RootCertStore::empty();
+        let ca = std::fs::read(dir.join("ca.pem")).unwrap();
+        for cert in rustls_pemfile::certs(&mut ca.as_slice()) {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let tls_config = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(tls_config));
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let conn = connector
+            .connect(server_name, pending.into_inner())
+            .await
+            .unwrap();
+        let mut conn = BufReader::new(conn);
+        line.clear();
+        let closed = tokio::time::timeout(Duration::from_secs(5), conn.read_line(&mut line))
+            .await
+            .expect("connection not closed");
+        assert!(!matches!(closed, Ok(n) if n > 0));
+        let s = state.lock().await;
+        assert!(s.clients.is_empty());
+        assert_eq!(s.pending_clients, 0);
+        drop(s);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    async fn start_cluster_node(routes: Vec<String>) -> ServerHandle<TrieSubList> {
+        start_cluster_node_with(ServerConfig::default(), routes).await
+    }
+
+    async fn start_cluster_node_with(
+        config: ServerConfig,
+        routes: Vec<String>,
+    ) -> ServerHandle<TrieSubList> {
+        let config = ServerConfig {
+            port: 0,
+            cluster: Some(ClusterConfig {
+                name: "test".to_string(),
+                port: 0,
+                routes,
+                connect_retry: Duration::from_millis(50),
+                ..config.cluster.clone().unwrap_or_default()
+            }),
+            ..config
+        };
+        Server::new(TrieSubList::default(), config)
+            .start()
+            .await
+            .unwrap()
+    }
+
+    async fn wait_for<F: Fn(&ServerState<TrieSubList>) -> bool>(
+        handle: &ServerHandle<TrieSubList>,
+        f: F,
+    ) {
+        for _ in 0..500 {
+            if f(&*handle.state.lock().await) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("timeout waiting for cluster state");
+    }
+
+    async fn cluster_client(
+        handle: &ServerHandle<TrieSubList>,
+        data: &[u8],
+    ) -> BufReader<TcpStream> {
+        let mut conn = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+        let mut line = String::new();
+        conn.read_line(&mut line).await.unwrap();
+        conn.get_mut().write_all(data).await.unwrap();
+        conn.get_mut().write_all(b"PING\r\n").await.unwrap();
+        line.clear();
+        conn.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "PONG\r\n");
+        conn
+    }
+
+    // 读取消息直到收到done,返回之前收到的所有行
+    async fn read_until_done(conn: &mut BufReader<TcpStream>) -> Vec<String> {
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            tokio::time::timeout(Duration::from_secs(5), conn.read_line(&mut line))
+                .await
+                .expect("read timeout")
+                .unwrap();
+            if line.starts_with("MSG done") {
+                // 跳过done的消息体
+                conn.read_line(&mut line).await.unwrap();
+                return lines;
+            }
+            lines.push(line);
+        }
+    }
+
+    fn sub_count(state: &ServerState<TrieSubList>) -> usize {
+        state.accounts[GLOBAL_ACCOUNT].sub_list.count()
+    }
+
+    #[tokio::test]
+    async fn test_cluster() {
+        let a = start_cluster_node(Vec::new()).await;
+        let a_url = format!("nats-route://{}", a.cluster_addr().unwrap());
+        let b = start_cluster_node(vec![a_url.clone()]).await;
+        let b_url = format!("nats-route://{}", b.cluster_addr().unwrap());
+        let c = start_cluster_node(vec![a_url, b_url]).await;
+        for node in [&a, &b, &c] {
+            wait_for(node, |s| s.routes.len() == 2).await;
+        }
+
+        let mut sub_c = cluster_client(&c, b"SUB foo 1\r\nSUB work q 2\r\nSUB done 3\r\n").await;
+        let mut sub_b = cluster_client(&b, b"SUB work q 2\r\nSUB done 3\r\n").await;
+        // 每个节点都有全部5个订阅,其中其他节点的是remote订阅
+        for node in [&a, &b, &c] {
+            wait_for(node, |s| sub_count(s) == 5).await;
+        }
+
+        let mut publish = b"PUB foo 5\r\nhello\r\n".to_vec();
+        for i in 0..4 {
+            publish.extend_from_slice(format!("PUB work 1\r\n{}\r\n", i).as_bytes());
+        }
+        publish.extend_from_slice(b"PUB done 1\r\n.\r\n");
+        let _pub_a = cluster_client(&a, &publish).await;
+        let lines_c = read_until_done(&mut sub_c).await;
+        let lines_b = read_until_done(&mut sub_b).await;
+        assert_eq!(lines_c[..2], ["MSG foo 1 5\r\n", "hello\r\n"]);
+        assert!(!lines_b.iter().any(|l| l.starts_with("MSG foo")));
+        // queue group在整个集群中只投递一次
+        let works = lines_b
+            .iter()
+            .chain(lines_c.iter())
+            .filter(|l| l.starts_with("MSG work"))
+            .count();
+        assert_eq!(works, 4);
+
+        // 取消订阅后其他节点删除对应的remote订阅
+        sub_c.get_mut().write_all(b"UNSUB 1\r\n").await.unwrap();
+        for node in [&a, &b, &c] {
+            wait_for(node, |s| sub_count(s) == 4).await;
+        }
+
+        let state = c.state.clone();
+        c.shutdown().await;
+        assert!(state.lock().await.routes.is_empty());
+        for node in [&a, &b] {
+            wait_for(node, |s| s.routes.len() == 1 && sub_count(s) == 2).await;
+        }
+        a.shutdown().await;
+        b.shutdown().await;
+    }
+
+    async fn read_info(conn: &mut BufReader<TcpStream>) -> ServerInfo {
+        let mut line = String::new();
+        tokio::time::timeout(Duration::from_secs(5), conn.read_line(&mut line))
+            .await
+            .expect("read timeout")
+            .unwrap();
+        serde_json::from_str(&line[5..line.len() - 2]).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_cluster_account_max_subscriptions() {
+        let config: ServerConfig =
+            serde_json::from_str(r#"{"accounts":{"$G":{"max_subscriptions":2}}}"#).unwrap();
+        let a = start_cluster_node_with(config.clone(), Vec::new()).await;
+        let a_url = format!("nats-route://{}", a.cluster_addr().unwrap());
+        let b = start_cluster_node_with(config, vec![a_url]).await;
+        wait_for(&a, |s| s.routes.len() == 1).await;
+        wait_for(&b, |s| s.routes.len() == 1).await;
+
+        // 其他服务端的订阅不占用本服务端账户的订阅数量
+        let _sub_b = cluster_client(&b, b"SUB foo 1\r\nSUB bar 2\r\n").await;
+        wait_for(&a, |s| sub_count(s) == 2).await;
+        let mut sub_a = cluster_client(&a, b"SUB foo 1\r\nSUB bar 2\r\n").await;
+        sub_a.get_mut().write_all(b"SUB baz 3\r\n").await.unwrap();
+        let mut line = String::new();
+        sub_a.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "-ERR 'maximum subscriptions exceeded'\r\n");
+        let s = a.state.lock().await;
+        assert_eq!(s.stats.account_max_subscriptions_exceeded, 1);
+        assert_eq!(s.accounts[GLOBAL_ACCOUNT].client_subs, 2);
+        assert_eq!(sub_count(&s), 4);
+        drop(s);
+        a.shutdown().await;
+        b.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_cluster_authorization() {
+        let config: ServerConfig = serde_json::from_str(
+            r#"{"cluster":{"authorization":{"user":"ruser","password":"pwd"}}}"#,
+        )
+        .unwrap();
+        let a = start_cluster_node_with(config, Vec::new()).await;
+        let addr = a.cluster_addr().unwrap();
+        let c = start_cluster_node(vec![format!("nats-route://ruser:bad@{}", addr)]).await;
+        let b = start_cluster_node(vec![format!("nats-route://ruser:pwd@{}", addr)]).await;
+        wait_for(&a, |s| s.routes.len() == 1).await;
+        wait_for(&b, |s| s.routes.len() == 1).await;
+        // 密码错误的c一直在重连,但是不会建立路由
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(c.state.lock().await.routes.is_empty());
+        assert_eq!(a.state.lock().await.routes.len(), 1);
+
+        // 没有CONNECT直接发送INFO
+        let info = ServerInfo {
+            server_id: "X".to_string(),
+            ..Default::default()
+        };
+        let mut conn = BufReader::new(TcpStream::connect(addr).await.unwrap());
+        assert!(read_info(&mut conn).await.auth_required);
+        conn.get_mut().write_all(&info.to_protocol()).await.unwrap();
+        let mut line = String::new();
+        conn.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "-ERR 'Authorization Violation'\r\n");
+
+        // 对端的订阅只能使用本服务端已有的账户
+        let mut conn = BufReader::new(TcpStream::connect(addr).await.unwrap());
+        read_info(&mut conn).await;
+        let mut hello = br#"CONNECT {"user":"ruser","pass":"pwd"}"#.to_vec();
+        hello.extend_from_slice(b"\r\n");
+        hello.extend_from_slice(&info.to_protocol());
+        hello.extend_from_slice(b"RS+ unknown foo\r\nRS+ $G bar\r\n");
+        conn.get_mut().write_all(&hello).await.unwrap();
+        wait_for(&a, |s| sub_count(s) == 1).await;
+        let s = a.state.lock().await;
+        assert_eq!(s.routes.len(), 2);
+        assert!(!s.accounts.contains_key("unknown"));
+        drop(s);
+        a.shutdown().await;
+        b.shutdown().await;
+        c.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_cluster_discovery() {
+        // b和c只配置了a,通过a转发的INFO互相建立路由
+        let a = start_cluster_node(Vec::new()).await;
+        let a_url = format!("nats-route://{}", a.cluster_addr().unwrap());
+        let b = start_cluster_node(vec![a_url.clone()]).await;
+        let c = start_cluster_node(vec![a_url]).await;
+        for node in [&a, &b, &c] {
+            wait_for(node, |s| s.routes.len() == 2).await;
+        }
+        let url = |node: &ServerHandle<TrieSubList>| node.local_addr().to_string();
+
+        let mut conn = BufReader::new(TcpStream::connect(a.local_addr()).await.unwrap());
+        let mut connect_urls = read_info(&mut conn).await.connect_urls;
+        assert_eq!(connect_urls[0], url(&a));
+        connect_urls.sort();
+        let mut expected = vec![url(&a), url(&b), url(&c)];
+        expected.sort();
+        assert_eq!(connect_urls, expected);
+
+        // 服务端离开集群后通知客户端新的地址列表
+        let c_url = url(&c);
+        c.shutdown().await;
+        let info = read_info(&mut conn).await;
+        assert_eq!(info.client_id, 0);
+        assert_eq!(info.connect_urls.len(), 2);
+        assert!(!info.connect_urls.contains(&c_url));
+        a.shutdown().await;
+        b.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_leaf_node() {
+        let hub_config: ServerConfig = serde_json::from_str(
+            r#"{"port":0,"leafnodes":{"port":0},"accounts":{
+                "A":{"users":[{"user":"a","pass":"a"}]},
+                "B":{"users":[{"user":"b","pass":"b"}]}
+            }}"#,
+        )
+        .unwrap();
+        let hub = Server::new(TrieSubList::default(), hub_config)
+            .start()
+            .await
+            .unwrap();
+        // 叶子节点的全局账户绑定到hub的账户A
+        let url = format!("nats-leaf://a:a@{}", hub.leaf_addr().unwrap());
+        let leaf_config = ServerConfig {
+            port: 0,
+            leafnodes: Some(leaf::LeafNodeConfig {
+                remotes: vec![leaf::RemoteLeafConfig {
+                    url,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let leaf = Server::new(TrieSubList::default(), leaf_config)
+            .start()
+            .await
+            .unwrap();
+        assert_eq!(leaf.leaf_addr(), None);
+        wait_for(&hub, |s| s.leafs.len() == 1).await;
+        wait_for(&leaf, |s| s.leafs.len() == 1).await;
+        let account_subs = |name: &'static str| {
+            move |s: &ServerState<TrieSubList>| {
+                s.accounts.get(name).map_or(0, |a| a.sub_list.count())
+            }
+        };
+
+        // 只有账户A的订阅传播给叶子节点
+        let mut hub_a = cluster_client(
+            &hub,
+            b"CONNECT {\"user\":\"a\",\"pass\":\"a\"}\r\nSUB foo 1\r\n",
+        )
+        .await;
+        let mut hub_b = cluster_client(
+            &hub,
+            b"CONNECT {\"user\":\"b\",\"pass\":\"b\"}\r\nSUB foo 1\r\n",
+        )
+        .await;
+        wait_for(&leaf, |s| sub_count(s) == 1).await;
+        let mut leaf_sub = cluster_client(&leaf, b"SUB bar 1\r\n").await;
+        wait_for(&hub, |s| account_subs("A")(s) == 2).await;
+        assert_eq!(account_subs("B")(&*hub.state.lock().await), 1);
+
+        let _leaf_pub = cluster_client(&leaf, b"PUB foo 2\r\nhi\r\nPUB nobody 1\r\nx\r\n").await;
+        let mut line = String::new();
+        hub_a.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "MSG foo 1 2\r\n");
+        hub_a.read_line(&mut line).await.unwrap();
+        hub_b
+            .get_mut()
+            .write_all(b"PUB bar 1\r\nb\r\nPING\r\n")
+            .await
+            .unwrap();
+        line.clear();
+        hub_b.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "PONG\r\n");
+        hub_a
+            .get_mut()
+            .write_all(b"PUB bar 1\r\na\r\n")
+            .await
+            .unwrap();
+        line.clear();
+        leaf_sub.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "MSG bar 1 1\r\n");
+        line.clear();
+        leaf_sub.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "a\r\n");
+
+        // 叶子节点断开后hub删除它的订阅
+        leaf.shutdown().await;
+        wait_for(&hub, |s| s.leafs.is_empty() && account_subs("A")(s) == 1).await;
+        hub.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_leaf_node_permissions() {
+        let hub_config: ServerConfig = serde_json::from_str(
+            r#"{"port":0,"leafnodes":{"port":0},"authorization":{"timeout":0.2,"users":[
+                {"user":"l","pass":"l","permissions":{
+                    "publish":"pub.>","subscribe":{"allow":"sub.>","deny":"sub.secret"}
+                }},
+                {"user":"c","pass":"c"}
+            ]}}"#,
+        )
+        .unwrap();
+        let hub = Server::new(TrieSubList::default(), hub_config)
+            .start()
+            .await
+            .unwrap();
+        let mut hub_c = cluster_client(
+            &hub,
+            b"CONNECT {\"user\":\"c\",\"pass\":\"c\"}\r\nSUB > 1\r\n",
+        )
+        .await;
+
+        // 没有权限的LS+和LMSG被忽略
+        let mut conn = BufReader::new(TcpStream::connect(hub.leaf_addr().unwrap()).await.unwrap());
+        read_info(&mut conn).await;
+        conn.get_mut()
+            .write_all(
+                b"CONNECT {\"user\":\"l\",\"pass\":\"l\"}\r\nLS+ sub.>\r\nLS+ other\r\n\
+                LMSG secret 1\r\ns\r\nLMSG pub.x 1\r\np\r\nPING\r\n",
+            )
+            .await
+            .unwrap();
+        let mut lines = Vec::new();
+        while lines.last().is_none_or(|l| l != "PONG\r\n") {
+            let mut line = String::new();
+            conn.read_line(&mut line).await.unwrap();
+            lines.push(line);
+        }
+        assert!(lines
+            .contains(&"-ERR 'Permissions Violation for Subscription to other'\r\n".to_string()));
+        assert!(
+            lines.contains(&"-ERR 'Permissions Violation for Publish to secret'\r\n".to_string())
+        );
+        let mut line = String::new();
+        hub_c.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "MSG pub.x 1 1\r\n");
+        assert_eq!(sub_count(&*hub.state.lock().await), 2);
+
+        // 通配符订阅中被拒绝的主题不转发给叶子节点
+        hub_c
+            .get_mut()
+            .write_all(b"PUB sub.secret 1\r\ns\r\nPUB sub.ok 1\r\no\r\n")
+            .await
+            .unwrap();
+        line.clear();
+        while !line.starts_with("LMSG") {
+            line.clear();
+            conn.read_line(&mut line).await.unwrap();
+        }
+        assert_eq!(line, "LMSG sub.ok 1\r\n");
+
+        // 一直没有CONNECT的连接在认证超时后断开
+        let mut conn = BufReader::new(TcpStream::connect(hub.leaf_addr().unwrap()).await.unwrap());
+        read_info(&mut conn).await;
+        line.clear();
+        conn.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "-ERR 'Authentication Timeout'\r\n");
+        hub.shutdown().await;
+    }
+
+    async fn start_gateway_node(
+        name: &str,
+        gateways: Vec<gateway::RemoteGatewayConfig>,
+    ) -> ServerHandle<TrieSubList> {
+        start_gateway_node_with(ServerConfig::default(), name, gateways).await
+    }
+
+    async fn start_gateway_node_with(
+        config: ServerConfig,
+        name: &str,
+        gateways: Vec<gateway::RemoteGatewayConfig>,
+    ) -> ServerHandle<TrieSubList> {
+        let config = ServerConfig {
+            port: 0,
+            gateway: Some(gateway::GatewayConfig {
+                name: name.to_string(),
+                port: 0,
+                gateways,
+                connect_retry: Duration::from_millis(50),
+                interest_only_threshold: 2,
+                ..config.gateway.clone().unwrap_or_default()
+            }),
+            ..config
+        };
+        Server::new(TrieSubList::default(), config)
+            .start()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_gateway() {
+        let a = start_gateway_node("A", Vec::new()).await;
+        let remote = gateway::RemoteGatewayConfig {
+            name: "A".to_string(),
+            urls: vec![format!("nats://{}", a.gateway_addr().unwrap())],
+        };
+        // a根据b的INFO建立反方向的outbound
+        let b = start_gateway_node("B", vec![remote]).await;
+        for node in [&a, &b] {
+            wait_for(node, |s| {
+                s.gateways.len() == 1 && s.inbound_gateways.len() == 1
+            })
+            .await;
+        }
+
+        // 普通订阅在乐观模式下不传播,queue group的订阅总是传播
+        let mut sub_b = cluster_client(&b, b"SUB foo 1\r\nSUB work q 2\r\nSUB done 3\r\n").await;
+        let mut sub_a = cluster_client(&a, b"SUB work q 2\r\n").await;
+        wait_for(&a, |s| sub_count(s) == 2).await;
+        wait_for(&b, |s| sub_count(s) == 4).await;
+
+        let mut publish = b"PUB foo 1\r\nf\r\n".to_vec();
+        for subject in ["n1", "n2", "n3"] {
+            publish.extend_from_slice(format!("PUB {} 1\r\nx\r\n", subject).as_bytes());
+        }
+        for _ in 0..3 {
+            publish.extend_from_slice(b"PUB work 1\r\nw\r\n");
+        }
+        publish.extend_from_slice(b"PUB done 1\r\n.\r\n");
+        let mut pub_a = cluster_client(&a, &publish).await;
+        let lines_b = read_until_done(&mut sub_b).await;
+        assert_eq!(lines_b, ["MSG foo 1 1\r\n", "f\r\n"]);
+        // queue group优先投递给本集群的成员
+        for _ in 0..3 {
+            let mut line = String::new();
+            sub_a.read_line(&mut line).await.unwrap();
+            assert_eq!(line, "MSG work 2 1\r\n");
+            sub_a.read_line(&mut line).await.unwrap();
+        }
+
+        // 超过阈值后切换到interest-only模式,a收到b的所有普通订阅
+        wait_for(&a, |s| {
+            let mode = s.gateways["B"].accounts.get(GLOBAL_ACCOUNT).map(|a| a.mode);
+            mode == Some(gateway::GatewayMode::InterestOnly) && sub_count(s) == 4
+        })
+        .await;
+
+        // 本集群没有成员时queue group发给其他集群
+        sub_a.get_mut().write_all(b"UNSUB 2\r\n").await.unwrap();
+        wait_for(&b, |s| sub_count(s) == 3).await;
+        pub_a
+            .get_mut()
+            .write_all(b"PUB n4 1\r\nx\r\nPUB work 1\r\nw\r\nPUB foo 1\r\nf\r\nPUB done 1\r\n.\r\n")
+            .await
+            .unwrap();
+        let lines_b = read_until_done(&mut sub_b).await;
+        assert_eq!(
+            lines_b,
+            ["MSG work 2 1\r\n", "w\r\n", "MSG foo 1 1\r\n", "f\r\n"]
+        );
+
+        b.shutdown().await;
+        wait_for(&a, |s| {
+            s.gateways.is_empty() && s.inbound_gateways.is_empty()
+        })
+        .await;
+        a.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_gateway_authorization() {
+        let config: ServerConfig = serde_json::from_str(
+            r#"{"gateway":{"authorization":{"user":"guser","password":"pwd"}}}"#,
+        )
+        .unwrap();
+        let a = start_gateway_node_with(config.clone(), "A", Vec::new()).await;
+        let addr = a.gateway_addr().unwrap();
+        let remote = |url: String| gateway::RemoteGatewayConfig {
+            name: "A".to_string(),
+            urls: vec![url],
+        };
+        let c = start_gateway_node("C", vec![remote(format!("nats://guser:bad@{}", addr))]).await;
+        // a建立反方向的outbound时使用自己authorization中的user/password
+        let b = start_gateway_node_with(
+            config,
+            "B",
+            vec![remote(format!("nats://guser:pwd@{}", addr))],
+        )
+        .await;
+        for node in [&a, &b] {
+            wait_for(node, |s| {
+                s.gateways.len() == 1 && s.inbound_gateways.len() == 1
+            })
+            .await;
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(c.state.lock().await.gateways.is_empty());
+        assert!(a.state.lock().await.gateways.contains_key("B"));
+
+        // 没有认证信息的CONNECT
+        let mut conn = BufReader::new(TcpStream::connect(addr).await.unwrap());
+        read_info(&mut conn).await;
+        conn.get_mut()
+            .write_all(b"CONNECT {\"gateway\":\"X\"}\r\n")
+            .await
+            .unwrap();
+        let mut line = String::new();
+        conn.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "-ERR 'Authorization Violation'\r\n");
+
+        // 发送到不存在的账户的消息被忽略
+        let mut conn = BufReader::new(TcpStream::connect(addr).await.unwrap());
+        read_info(&mut conn).await;
+        conn.get_mut()
+            .write_all(
+                b"CONNECT {\"gateway\":\"X\",\"user\":\"guser\",\"pass\":\"pwd\"}\r\n\
+                RMSG unknown foo 1\r\nx\r\nPING\r\n",
+            )
+            .await
+            .unwrap();
+        line.clear();
+        conn.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "PONG\r\n");
+        let s = a.state.lock().await;
+        assert_eq!(s.inbound_gateways.len(), 2);
+        assert!(!s.accounts.contains_key("unknown"));
+        drop(s);
+        a.shutdown().await;
+        b.shutdown().await;
+        c.shutdown().await;
+    }
+
+    async fn start_jetstream_node(store_dir: &std::path::Path) -> ServerHandle<TrieSubList> {
+        let config = ServerConfig {
+            port: 0,
+            jetstream: Some(crate::jetstream::JetStreamConfig {
+                store_dir: store_dir.to_path_buf(),
+            }),
+            ..Default::default()
+        };
+        Server::new(TrieSubList::default(), config)
+            .start()
+            .await
+            .unwrap()
+    }
+
+    // 发送请求,conn需要订阅了_INBOX.1,返回JSON格式的响应
+    async fn request(
+        conn: &mut BufReader<TcpStream>,
+        subject: &str,
+        payload: &str,
+    ) -> serde_json::Value {
+        let msg = format!(
+            "PUB {} _INBOX.1 {}\r\n{}\r\n",
+            subject,
+            payload.len(),
+            payload
+        );
+        conn.get_mut().write_all(msg.as_bytes()).await.unwrap();
+        let mut line = String::new();
+        tokio::time::timeout(Duration::from_secs(5), conn.read_line(&mut line))
+            .await
+            .expect("read timeout")
+            .unwrap();
+        assert!(line.starts_with("MSG _INBOX.1 1 "), "{}", line);
+        line.clear();
+        conn.read_line(&mut line).await.unwrap();
+        serde_json::from_str(line.trim_end()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_jetstream() {
+        use serde_json::json;
+        let dir = std::env::temp_dir().join(format!("msgnats-jetstream-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let node = start_jetstream_node(&dir).await;
+        let mut conn = cluster_client(&node, b"SUB _INBOX.1 1\r\n").await;
+        let r = request(
+            &mut conn,
+            "$JS.API.STREAM.CREATE.ORDERS",
+            r#"{"subjects":["orders.*"],"max_msgs":2}"#,
+        )
+        .await;
+        assert_eq!(r["config"]["subjects"], json!(["orders.*"]));
+        assert_eq!(r["state"]["messages"], 0);
+        let r = request(
+            &mut conn,
+            "$JS.API.STREAM.CREATE.OTHER",
+            r#"{"subjects":["*.created"]}"#,
+        )
+        .await;
+        assert_eq!(r["error"]["code"], 400);
+        // 没有订阅者的消息也会保存,带reply_to时回复序号
+        for i in 1..=3 {
+            let r = request(&mut conn, &format!("orders.{}", i), &format!("order {}", i)).await;
+            assert_eq!(r, json!({"stream": "ORDERS", "seq": i}));
+        }
+        node.shutdown().await;
+
+        // 重启后恢复stream,序号继续递增
+        let node = start_jetstream_node(&dir).await;
+        let mut conn = cluster_client(&node, b"SUB _INBOX.1 1\r\n").await;
+        // 没有参数的请求和NATS客户端一样不带payload
+        let r = request(&mut conn, "$JS.API.STREAM.INFO.ORDERS", "").await;
+        assert_eq!(r["state"]["messages"], 2);
+        assert_eq!(r["state"]["first_seq"], 2);
+        assert_eq!(r["state"]["last_seq"], 3);
+        let r = request(&mut conn, "$JS.API.STREAM.MSG.GET.ORDERS", r#"{"seq":3}"#).await;
+        assert_eq!(r["message"]["subject"], "orders.3");
+        assert_eq!(r["message"]["data"], "b3JkZXIgMw==");
+        let r = request(&mut conn, "$JS.API.STREAM.MSG.GET.ORDERS", r#"{"seq":1}"#).await;
+        assert_eq!(r["error"]["code"], 404);
+        let r = request(&mut conn, "orders.4", "x").await;
+        assert_eq!(r["seq"], 4);
+        // 修改主题后只订阅新的主题
+        let r = request(
+            &mut conn,
+            "$JS.API.STREAM.UPDATE.ORDERS",
+            r#"{"subjects":["shipped.*"],"max_msgs":2}"#,
+        )
+        .await;
+        assert_eq!(r["config"]["subjects"], json!(["shipped.*"]));
+        let r = request(&mut conn, "shipped.1", "x").await;
+        assert_eq!(r["seq"], 5);
+        let s = node.state.lock().await;
+        let orders = s.accounts[GLOBAL_ACCOUNT]
+            .sub_list
+            .match_subject("orders.5");
+        assert!(orders.unwrap().is_empty());
+        drop(s);
+        let r = request(&mut conn, "$JS.API.STREAM.NAMES", "").await;
+        assert_eq!(r, json!({"streams": ["ORDERS"]}));
+        let r = request(&mut conn, "$JS.API.STREAM.PURGE.ORDERS", "").await;
+        assert_eq!(r, json!({"success": true, "purged": 2}));
+        let r = request(&mut conn, "$JS.API.STREAM.DELETE.ORDERS", "").await;
+        assert_eq!(r, json!({"success": true}));
+        let r = request(&mut conn, "$JS.API.STREAM.INFO.ORDERS", "").await;
+        assert_eq!(r["error"]["code"], 404);
+        assert!(!dir
+            .join(GLOBAL_ACCOUNT)
+            .join("streams")
+            .join("ORDERS")
+            .exists());
+        node.shutdown().await;
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_advertise_addrs() {
+        let addr: SocketAddr = "127.0.0.1:4222".parse().unwrap();
+        assert_eq!(advertise_addrs(addr), vec![addr]);
+        let addrs = advertise_addrs("0.0.0.0:4222".parse().unwrap());
+        assert!(!addrs.is_empty());
+        for addr in addrs {
+            assert!(addr.is_ipv4() && !addr.ip().is_unspecified());
+            assert_eq!(addr.port(), 4222);
+        }
     }
 }
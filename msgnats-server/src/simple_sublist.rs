@@ -1,11 +1,14 @@
 use crate::client::ClientMessageSender;
 use crate::errors::Result;
-use futures::lock::Mutex;
 use std::{
     cmp::Ordering,
     collections::{BTreeSet, HashMap},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering as AtomicOrdering},
+        Arc,
+    },
 };
+use tokio::sync::Mutex;
 
 /**
 考虑到Trie的实现以及Cache的实现都是很琐碎,
@@ -13,6 +16,17 @@ use std::{
 这样就是简单的字符串查找了. 使用map即可.
 但是为了后续的扩展性呢,我会定义SubListTrait,这样方便后续实现Trie树
 */
+/**
+ * 订阅来自的连接类型,除了客户端之外都是其他服务端的订阅,消息转发给对应的连接
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnKind {
+    Client,
+    Route,   // 同一个集群中的其他服务端
+    Leaf,    // 叶子节点连接的另一端
+    Gateway, // 其他集群,只在本服务端使用,不传播给路由和叶子节点
+}
+
 //  订阅消息描述结构体
 #[derive(Debug)]
 pub struct SubScription {
@@ -20,10 +34,25 @@ pub struct SubScription {
     pub subject: String,
     pub queue: Option<String>,
     pub sid: String,
+    pub kind: ConnKind,
+    max_msgs: AtomicUsize,    // UNSUB设置的最大投递数量,0表示不限制
+    delivered: AtomicUsize,   // 已经投递的消息数量
+    last_delivery: AtomicU64, // 最后一次投递时的全局投递序号,0表示还没有投递过
+}
+
+// 全局投递序号,用来比较订阅者最后一次收到消息的先后
+static DELIVERY_SEQ: AtomicU64 = AtomicU64::new(0);
+
+// 投递前检查订阅的投递数量限制
+#[derive(Debug, PartialEq, Eq)]
+pub enum Delivery {
+    Deliver, // 正常投递
+    Last,    // 投递后达到上限,需要自动取消订阅
+    Skip,    // 已经达到上限,不再投递
 }
 
 impl SubScription {
-    fn new(
+    pub fn new(
         msg_sender: Arc<Mutex<ClientMessageSender>>,
         subject: &str,
         queue: Option<&str>,
@@ -34,6 +63,62 @@ impl SubScription {
             subject: subject.to_string(),
             queue: queue.map(|q| q.to_string()),
             sid: sid.to_string(),
+            kind: ConnKind::Client,
+            max_msgs: AtomicUsize::new(0),
+            delivered: AtomicUsize::new(0),
+            last_delivery: AtomicU64::new(0),
+        }
+    }
+
+    /**
+     * 路由、叶子节点或者网关上收到的RS+/LS+,msg_sender是对应连接的写端
+     */
+    pub fn new_remote(
+        kind: ConnKind,
+        msg_sender: Arc<Mutex<ClientMessageSender>>,
+        subject: &str,
+        queue: Option<&str>,
+        sid: &str,
+    ) -> Self {
+        Self {
+            kind,
+            ..Self::new(msg_sender, subject, queue, sid)
+        }
+    }
+
+    // 其他服务端的订阅
+    pub fn is_remote(&self) -> bool {
+        self.kind != ConnKind::Client
+    }
+
+    pub fn last_delivery(&self) -> u64 {
+        self.last_delivery.load(AtomicOrdering::SeqCst)
+    }
+
+    /**
+     * 设置最大投递数量,返回是否已经达到上限
+     */
+    pub fn set_max_msgs(&self, max_msgs: usize) -> bool {
+        self.max_msgs.store(max_msgs, AtomicOrdering::SeqCst);
+        max_msgs > 0 && self.delivered.load(AtomicOrdering::SeqCst) >= max_msgs
+    }
+
+    /**
+     * 每次投递前调用,并发投递时只有一个发布者会拿到Last
+     */
+    pub fn acquire_delivery(&self) -> Delivery {
+        let max_msgs = self.max_msgs.load(AtomicOrdering::SeqCst);
+        let delivered = self.delivered.fetch_add(1, AtomicOrdering::SeqCst) + 1;
+        if max_msgs == 0 || delivered <= max_msgs {
+            let seq = DELIVERY_SEQ.fetch_add(1, AtomicOrdering::SeqCst) + 1;
+            self.last_delivery.store(seq, AtomicOrdering::SeqCst);
+        }
+        if max_msgs == 0 || delivered < max_msgs {
+            Delivery::Deliver
+        } else if delivered == max_msgs {
+            Delivery::Last
+        } else {
+            Delivery::Skip
         }
     }
 }
@@ -56,7 +141,7 @@ impl SubResult {
     }
 
     pub fn is_empty(&self) -> bool {
-        self.ppubs.len() == 0 && self.qpubs.len() == 0
+        self.ppubs.is_empty() && self.qpubs.is_empty()
     }
 }
 
@@ -98,12 +183,14 @@ impl std::cmp::PartialOrd for ArcSubscriptionWrapper {
 pub type ArcSubResult = Arc<SubResult>;
 // SubListTrait是他对外提供的服务接口,主要是
 // 1. 新增订阅 这个是当一个Client 发送sub消息到服务端的时候要处理的
-// 2. 删除订阅 这个是当一个Client发送 unsub消息到服务端的时候要处理的,不过因为我们不支持unsub,那就是连接断开的时候处理的.
+// 2. 删除订阅 这个是当一个Client发送 unsub消息,订阅达到max_msgs自动取消,或者连接断开的时候要处理的.
 // 3. 查找相关订阅 这个是当一个client发送pub消息到服务端后,服务端要查找所有相关的订阅,然后把消息逐一转发给他们.
 pub trait SubListTrait {
     fn insert(&mut self, sub: ArcSubscription) -> Result<()>;
     fn remove(&mut self, sub: ArcSubscription) -> Result<()>;
     fn match_subject(&self, subject: &str) -> Result<ArcSubResult>;
+    // 当前订阅数量,用于检查账户的最大订阅数
+    fn count(&self) -> usize;
 }
 // 订阅列表 SimpleSubList中,BTreeSeet中的存放的是ArcSubscriptionWrapper,而不是ArcSubscriptionWrapper.
 // 这是有意为之的,因为我们在向BTreeSet中插入新的Sub的时候不需要关心他们真实的顺序,只是需要关心他们是否相同. 所以我们比较的对象是他们的地址而不是内容.
@@ -120,17 +207,11 @@ impl SubListTrait for SimpleSubList {
      */
     fn insert(&mut self, sub: Arc<SubScription>) -> Result<()> {
         if let Some(ref q) = sub.queue {
-            let qsubs = self
-                .qsubs
-                .entry(sub.subject.clone())
-                .or_insert(Default::default());
-            let subs = qsubs.entry(q.clone()).or_insert(Default::default());
+            let qsubs = self.qsubs.entry(sub.subject.clone()).or_default();
+            let subs = qsubs.entry(q.clone()).or_default();
             subs.insert(ArcSubscriptionWrapper(sub));
         } else {
-            let subs = self
-                .subs
-                .entry(sub.subject.clone())
-                .or_insert(Default::default());
+            let subs = self.subs.entry(sub.subject.clone()).or_default();
             // 零成本抽象
             subs.insert(ArcSubscriptionWrapper(sub));
         }
@@ -143,10 +224,8 @@ impl SubListTrait for SimpleSubList {
         if let Some(ref q) = sub.queue {
             if let Some(qsubs) = self.qsubs.get_mut(&sub.subject) {
                 if let Some(subs) = qsubs.get_mut(q) {
-                    if subs.remove(&ArcSubscriptionWrapper(sub.clone())) {
-                        if subs.is_empty() {
-                            qsubs.remove(q);
-                        }
+                    if subs.remove(&ArcSubscriptionWrapper(sub.clone())) && subs.is_empty() {
+                        qsubs.remove(q);
                     }
                 }
                 if qsubs.is_empty() {
@@ -154,14 +233,10 @@ impl SubListTrait for SimpleSubList {
                     self.qsubs.remove(&sub.subject);
                 }
             }
-        } else {
-            if let Some(subs) = self.subs.get_mut(&sub.subject) {
-                if subs.remove(&ArcSubscriptionWrapper(sub.clone())) {
-                    if subs.is_empty() {
-                        // 不存在值 清空
-                        self.subs.remove(&sub.subject);
-                    }
-                }
+        } else if let Some(subs) = self.subs.get_mut(&sub.subject) {
+            if subs.remove(&ArcSubscriptionWrapper(sub.clone())) && subs.is_empty() {
+                // 不存在值 清空
+                self.subs.remove(&sub.subject);
             }
         }
         Ok(())
@@ -189,4 +264,15 @@ impl SubListTrait for SimpleSubList {
         }
         Ok(Arc::new(r))
     }
+
+    fn count(&self) -> usize {
+        let psubs: usize = self.subs.values().map(|subs| subs.len()).sum();
+        let qsubs: usize = self
+            .qsubs
+            .values()
+            .flat_map(|q| q.values())
+            .map(|s| s.len())
+            .sum();
+        psubs + qsubs
+    }
 }
@@ -0,0 +1,529 @@
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_derive::{Deserialize, Serialize};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader, SeekFrom};
+
+use crate::config::duration_format;
+
+/**
+stream把发布到subjects上的消息按顺序保存到文件中,每条消息有递增的序号以及保存时的时间戳.
+每个stream一个目录:
+```text
+<store_dir>/<account>/streams/<name>/meta.json  stream的配置以及创建时间
+<store_dir>/<account>/streams/<name>/msgs.log   消息日志
+```
+消息日志以8字节的第一条消息序号开头,之后依次追加消息记录,整数都是小端:
+```text
+<record_len u32> <seq u64> <time u64> <subject_len u16> <subject> <hdr_len u32> <msg>
+```
+record_len是之后所有字段的长度,time是UNIX纳秒时间戳,hdr_len为0表示没有消息头.
+超过max_msgs、max_bytes或者max_age时从最旧的消息开始删除,删除时只更新文件开头的序号,
+删除的数据比剩下的还多时再重写日志文件.服务端异常退出时最后一条不完整的记录会被丢弃.
+*/
+pub const META_FILE: &str = "meta.json";
+pub const LOG_FILE: &str = "msgs.log";
+const LOG_HEADER_LEN: u64 = 8;
+// record_len之后固定长度的字段: seq time subject_len hdr_len
+const RECORD_FIXED_LEN: usize = 8 + 8 + 2 + 4;
+// 超过这个长度的记录认为日志文件已经损坏
+const MAX_RECORD_LEN: usize = 64 * 1024 * 1024;
+// 删除的数据超过这个大小并且超过剩下的数据时重写日志文件
+const COMPACT_MIN_BYTES: u64 = 64 * 1024;
+
+/**
+ * 数量相关的限制为0时表示不限制
+ */
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct StreamConfig {
+    pub name: String,
+    pub subjects: Vec<String>, // 为空时使用stream的名字作为主题
+    pub max_msgs: u64,
+    pub max_bytes: u64, // 所有消息记录的总长度
+    #[serde(with = "duration_format")]
+    pub max_age: Duration,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct StreamState {
+    pub messages: u64,
+    pub bytes: u64,
+    pub first_seq: u64, // 没有消息时是下一条消息的序号
+    pub last_seq: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredMessage {
+    pub seq: u64,
+    pub time: u64, // 保存时的UNIX纳秒时间戳
+    pub subject: String,
+    pub hdr_len: Option<usize>,
+    pub msg: Vec<u8>, // 带消息头时消息头在最前面
+}
+
+impl StoredMessage {
+    // 包括开头的record_len
+    fn encode(&self) -> Vec<u8> {
+        let len = RECORD_FIXED_LEN + self.subject.len() + self.msg.len();
+        let mut buf = Vec::with_capacity(4 + len);
+        buf.extend_from_slice(&(len as u32).to_le_bytes());
+        buf.extend_from_slice(&self.seq.to_le_bytes());
+        buf.extend_from_slice(&self.time.to_le_bytes());
+        buf.extend_from_slice(&(self.subject.len() as u16).to_le_bytes());
+        buf.extend_from_slice(self.subject.as_bytes());
+        buf.extend_from_slice(&(self.hdr_len.unwrap_or(0) as u32).to_le_bytes());
+        buf.extend_from_slice(&self.msg);
+        buf
+    }
+
+    // record不包括开头的record_len,格式不对时返回None
+    fn decode(record: &[u8]) -> Option<Self> {
+        let u64_at = |i: usize| record[i..i + 8].try_into().ok().map(u64::from_le_bytes);
+        if record.len() < RECORD_FIXED_LEN {
+            return None;
+        }
+        let subject_len = u16::from_le_bytes(record[16..18].try_into().ok()?) as usize;
+        let rest = &record[18..];
+        if rest.len() < subject_len + 4 {
+            return None;
+        }
+        let subject = String::from_utf8(rest[..subject_len].to_vec()).ok()?;
+        let hdr_len = u32::from_le_bytes(rest[subject_len..subject_len + 4].try_into().ok()?);
+        let msg = rest[subject_len + 4..].to_vec();
+        if hdr_len as usize > msg.len() {
+            return None;
+        }
+        Some(Self {
+            seq: u64_at(0)?,
+            time: u64_at(8)?,
+            subject,
+            hdr_len: (hdr_len > 0).then_some(hdr_len as usize),
+            msg,
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StreamMeta {
+    config: StreamConfig,
+    created: u64,
+}
+
+// 消息记录在日志文件中的位置
+#[derive(Debug)]
+struct MsgIndex {
+    seq: u64,
+    time: u64,
+    offset: u64,
+    len: u64, // 包括开头的record_len
+}
+
+/**
+ * 一个stream的消息日志,所有消息的位置保存在内存中,读取消息时再从文件中读
+ */
+#[derive(Debug)]
+pub struct Stream {
+    pub config: StreamConfig,
+    pub created: u64, // 创建时的UNIX纳秒时间戳
+    dir: PathBuf,
+    file: File,
+    index: VecDeque<MsgIndex>,
+    last_seq: u64,
+    bytes: u64, // 没有删除的消息记录的总长度
+    end: u64,   // 日志文件的长度
+}
+
+impl Stream {
+    /**
+     * 在dir中创建一个新的stream,dir中原来的数据会被覆盖
+     */
+    pub async fn create(dir: &Path, config: StreamConfig) -> Result<Self> {
+        fs::create_dir_all(dir).await?;
+        let created = now();
+        write_meta(dir, &config, created).await?;
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(dir.join(LOG_FILE))
+            .await?;
+        file.write_all(&1u64.to_le_bytes()).await?;
+        file.flush().await?;
+        Ok(Self {
+            config,
+            created,
+            dir: dir.to_path_buf(),
+            file,
+            index: VecDeque::new(),
+            last_seq: 0,
+            bytes: 0,
+            end: LOG_HEADER_LEN,
+        })
+    }
+
+    /**
+     * 服务端重启后从dir中恢复stream,恢复后按照当前的时间重新检查限制
+     */
+    pub async fn open(dir: &Path) -> Result<Self> {
+        let meta: StreamMeta = serde_json::from_slice(&fs::read(dir.join(META_FILE)).await?)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(dir.join(LOG_FILE))
+            .await?;
+        let mut index = VecDeque::new();
+        let (mut bytes, mut end) = (0, LOG_HEADER_LEN);
+        let mut reader = BufReader::new(&mut file);
+        let first_seq = reader.read_u64_le().await?;
+        let mut last_seq = first_seq.saturating_sub(1);
+        loop {
+            let len = match reader.read_u32_le().await {
+                Ok(len) => len as usize,
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            };
+            if !(RECORD_FIXED_LEN..=MAX_RECORD_LEN).contains(&len) {
+                break;
+            }
+            let mut record = vec![0u8; len];
+            match reader.read_exact(&mut record).await {
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            }
+            let msg = match StoredMessage::decode(&record) {
+                Some(msg) => msg,
+                None => break,
+            };
+            let size = 4 + len as u64;
+            // 文件开头的序号之前的消息已经删除
+            if msg.seq >= first_seq {
+                index.push_back(MsgIndex {
+                    seq: msg.seq,
+                    time: msg.time,
+                    offset: end,
+                    len: size,
+                });
+                bytes += size;
+            }
+            last_seq = last_seq.max(msg.seq);
+            end += size;
+        }
+        drop(reader);
+        // 丢弃最后不完整的记录
+        file.set_len(end).await?;
+        let mut stream = Self {
+            config: meta.config,
+            created: meta.created,
+            dir: dir.to_path_buf(),
+            file,
+            index,
+            last_seq,
+            bytes,
+            end,
+        };
+        stream.enforce_limits().await?;
+        Ok(stream)
+    }
+
+    pub fn state(&self) -> StreamState {
+        StreamState {
+            messages: self.index.len() as u64,
+            bytes: self.bytes,
+            first_seq: self.first_seq(),
+            last_seq: self.last_seq,
+        }
+    }
+
+    fn first_seq(&self) -> u64 {
+        self.index.front().map_or(self.last_seq + 1, |i| i.seq)
+    }
+
+    /**
+     * 追加一条消息,返回消息的序号
+     */
+    pub async fn store(
+        &mut self,
+        subject: &str,
+        hdr_len: Option<usize>,
+        msg: &[u8],
+    ) -> Result<u64> {
+        let message = StoredMessage {
+            seq: self.last_seq + 1,
+            time: now(),
+            subject: subject.to_string(),
+            hdr_len,
+            msg: msg.to_vec(),
+        };
+        let record = message.encode();
+        let max_bytes = self.config.max_bytes;
+        if max_bytes > 0 && record.len() as u64 > max_bytes {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "message size exceeds maximum bytes",
+            ));
+        }
+        self.file.seek(SeekFrom::Start(self.end)).await?;
+        self.file.write_all(&record).await?;
+        self.file.flush().await?;
+        self.index.push_back(MsgIndex {
+            seq: message.seq,
+            time: message.time,
+            offset: self.end,
+            len: record.len() as u64,
+        });
+        self.last_seq = message.seq;
+        self.bytes += record.len() as u64;
+        self.end += record.len() as u64;
+        self.enforce_limits().await?;
+        Ok(message.seq)
+    }
+
+    /**
+     * 按照序号读取一条消息,已经删除的返回None
+     */
+    pub async fn get(&mut self, seq: u64) -> Result<Option<StoredMessage>> {
+        let (offset, len) = match self.index.binary_search_by_key(&seq, |i| i.seq) {
+            Ok(i) => (self.index[i].offset, self.index[i].len),
+            Err(_) => return Ok(None),
+        };
+        let mut record = vec![0u8; (len - 4) as usize];
+        self.file.seek(SeekFrom::Start(offset + 4)).await?;
+        self.file.read_exact(&mut record).await?;
+        StoredMessage::decode(&record)
+            .map(Some)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "invalid message record"))
+    }
+
+    /**
+     * 修改配置,新的限制立即生效
+     */
+    pub async fn update(&mut self, config: StreamConfig) -> Result<()> {
+        write_meta(&self.dir, &config, self.created).await?;
+        self.config = config;
+        self.enforce_limits().await
+    }
+
+    /**
+     * 删除所有消息,序号继续递增,返回删除的消息数量
+     */
+    pub async fn purge(&mut self) -> Result<u64> {
+        let purged = self.index.len() as u64;
+        self.index.clear();
+        self.bytes = 0;
+        // 先更新序号再截断,中间退出时也不会让序号回退
+        self.write_first_seq().await?;
+        self.file.set_len(LOG_HEADER_LEN).await?;
+        self.end = LOG_HEADER_LEN;
+        Ok(purged)
+    }
+
+    // 删除stream的目录
+    pub async fn delete(self) -> Result<()> {
+        drop(self.file);
+        fs::remove_dir_all(&self.dir).await
+    }
+
+    /**
+     * 从最旧的消息开始删除超过限制的消息,max_age需要定时检查
+     */
+    pub async fn enforce_limits(&mut self) -> Result<()> {
+        let now = now();
+        let max_age = self.config.max_age.as_nanos() as u64;
+        let (max_msgs, max_bytes) = (self.config.max_msgs, self.config.max_bytes);
+        let mut removed = false;
+        while let Some(front) = self.index.front() {
+            let exceeded = (max_msgs > 0 && self.index.len() as u64 > max_msgs)
+                || (max_bytes > 0 && self.bytes > max_bytes)
+                || (max_age > 0 && now.saturating_sub(front.time) > max_age);
+            if !exceeded {
+                break;
+            }
+            self.bytes -= front.len;
+            self.index.pop_front();
+            removed = true;
+        }
+        if removed {
+            self.write_first_seq().await?;
+            self.compact().await?;
+        }
+        Ok(())
+    }
+
+    async fn write_first_seq(&mut self) -> Result<()> {
+        let first_seq = self.first_seq();
+        self.file.seek(SeekFrom::Start(0)).await?;
+        self.file.write_all(&first_seq.to_le_bytes()).await?;
+        self.file.flush().await
+    }
+
+    // 删除的数据比剩下的多时,把剩下的消息复制到新的日志文件中再替换
+    async fn compact(&mut self) -> Result<()> {
+        let dead = self.end - LOG_HEADER_LEN - self.bytes;
+        if dead < COMPACT_MIN_BYTES || dead < self.bytes {
+            return Ok(());
+        }
+        let start = self.index.front().map_or(self.end, |i| i.offset);
+        let path = self.dir.join(LOG_FILE);
+        let tmp_path = self.dir.join(format!("{}.tmp", LOG_FILE));
+        let mut tmp = File::create(&tmp_path).await?;
+        tmp.write_all(&self.first_seq().to_le_bytes()).await?;
+        self.file.seek(SeekFrom::Start(start)).await?;
+        tokio::io::copy(&mut (&mut self.file).take(self.end - start), &mut tmp).await?;
+        tmp.sync_all().await?;
+        drop(tmp);
+        fs::rename(&tmp_path, &path).await?;
+        self.file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .await?;
+        let shift = start - LOG_HEADER_LEN;
+        for i in self.index.iter_mut() {
+            i.offset -= shift;
+        }
+        self.end -= shift;
+        Ok(())
+    }
+}
+
+// 先写临时文件再改名,避免写了一半的配置
+async fn write_meta(dir: &Path, config: &StreamConfig, created: u64) -> Result<()> {
+    let meta = StreamMeta {
+        config: config.clone(),
+        created,
+    };
+    let data = serde_json::to_vec(&meta).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    let tmp_path = dir.join(format!("{}.tmp", META_FILE));
+    fs::write(&tmp_path, data).await?;
+    fs::rename(&tmp_path, dir.join(META_FILE)).await
+}
+
+// 当前的UNIX纳秒时间戳
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("msgnats-stream-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_record() {
+        let msg = StoredMessage {
+            seq: 7,
+            time: 42,
+            subject: "orders.1".to_string(),
+            hdr_len: Some(12),
+            msg: b"NATS/1.0\r\n\r\nhello".to_vec(),
+        };
+        let record = msg.encode();
+        assert_eq!(record.len(), 4 + RECORD_FIXED_LEN + 8 + 17);
+        assert_eq!(StoredMessage::decode(&record[4..]), Some(msg));
+        assert_eq!(StoredMessage::decode(&record[4..20]), None);
+    }
+
+    #[tokio::test]
+    async fn test_store_and_reopen() {
+        let dir = test_dir("reopen");
+        let config = StreamConfig {
+            name: "ORDERS".to_string(),
+            subjects: vec!["orders.>".to_string()],
+            max_msgs: 3,
+            ..Default::default()
+        };
+        let mut stream = Stream::create(&dir, config.clone()).await.unwrap();
+        for i in 1..=5u64 {
+            let seq = stream
+                .store(&format!("orders.{}", i), None, b"x")
+                .await
+                .unwrap();
+            assert_eq!(seq, i);
+        }
+        let state = stream.state();
+        assert_eq!((state.messages, state.first_seq, state.last_seq), (3, 3, 5));
+        assert_eq!(stream.get(2).await.unwrap(), None);
+        assert_eq!(stream.get(4).await.unwrap().unwrap().subject, "orders.4");
+        drop(stream);
+
+        // 写了一半的记录在重新打开时丢弃
+        let mut log = std::fs::OpenOptions::new()
+            .append(true)
+            .open(dir.join(LOG_FILE))
+            .unwrap();
+        std::io::Write::write_all(&mut log, &[40, 0, 0, 0, 6]).unwrap();
+        let mut stream = Stream::open(&dir).await.unwrap();
+        assert_eq!(stream.config, config);
+        assert_eq!(stream.state(), state);
+        assert_eq!(stream.store("orders.6", None, b"y").await.unwrap(), 6);
+        assert_eq!(stream.get(3).await.unwrap(), None);
+
+        // 清空之后序号继续递增
+        assert_eq!(stream.purge().await.unwrap(), 3);
+        drop(stream);
+        let mut stream = Stream::open(&dir).await.unwrap();
+        assert_eq!(stream.state().messages, 0);
+        assert_eq!(stream.state().first_seq, 7);
+        assert_eq!(stream.store("orders.7", None, b"z").await.unwrap(), 7);
+        stream.delete().await.unwrap();
+        assert!(!dir.exists());
+    }
+
+    #[tokio::test]
+    async fn test_limits_and_compact() {
+        let dir = test_dir("compact");
+        let config = StreamConfig {
+            name: "LOGS".to_string(),
+            max_bytes: 4 * COMPACT_MIN_BYTES,
+            ..Default::default()
+        };
+        let mut stream = Stream::create(&dir, config).await.unwrap();
+        let msg = vec![b'a'; 1000];
+        for _ in 0..1000 {
+            stream.store("logs", None, &msg).await.unwrap();
+        }
+        let state = stream.state();
+        assert!(state.bytes <= 4 * COMPACT_MIN_BYTES);
+        assert_eq!(state.last_seq, 1000);
+        // 删除的数据不会一直留在日志文件中
+        let len = std::fs::metadata(dir.join(LOG_FILE)).unwrap().len();
+        assert!(len <= LOG_HEADER_LEN + 2 * state.bytes + COMPACT_MIN_BYTES);
+        let last = stream.get(1000).await.unwrap().unwrap();
+        assert_eq!(last.msg, msg);
+        assert!(stream
+            .store("logs", None, &vec![b'b'; 300 * 1024])
+            .await
+            .is_err());
+        drop(stream);
+        let mut stream = Stream::open(&dir).await.unwrap();
+        assert_eq!(stream.state(), state);
+
+        stream
+            .update(StreamConfig {
+                max_age: Duration::from_millis(50),
+                ..stream.config.clone()
+            })
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        stream.enforce_limits().await.unwrap();
+        assert_eq!(stream.state().messages, 0);
+        assert_eq!(stream.state().first_seq, 1001);
+        stream.delete().await.unwrap();
+    }
+}
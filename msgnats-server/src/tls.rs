@@ -0,0 +1,175 @@
+use crate::config::duration_format;
+use serde_derive::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::{
+    self,
+    crypto::ring,
+    pki_types::{CertificateDer, PrivateKeyDer},
+    server::WebPkiClientVerifier,
+    RootCertStore,
+};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+/**
+客户端连接的TLS配置,和NATS的tls块一致:
+```text
+tls {
+    cert_file: "./certs/server.pem"
+    key_file: "./certs/server-key.pem"
+    ca_file: "./certs/ca.pem"   # 验证客户端证书
+    verify: true                # 要求客户端提供证书
+    verify_and_map: true        # 用客户端证书中的身份作为用户名认证
+    timeout: 2
+}
+```
+和NATS一样,服务端先明文发送带有tls_required的INFO,客户端收到后再发起TLS握手.
+
+verify_and_map时依次使用证书中的email、DNS名称以及subject(比如"CN=alice, O=Acme")
+查找authorization或者账户中用户名相同的用户,不需要密码.
+*/
+pub const DEFAULT_TLS_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct TlsConfig {
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+    pub ca_file: Option<PathBuf>,
+    pub verify: bool,
+    pub verify_and_map: bool,
+    #[serde(with = "duration_format")]
+    pub timeout: Duration, // TLS握手超时时间
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            cert_file: PathBuf::new(),
+            key_file: PathBuf::new(),
+            ca_file: None,
+            verify: false,
+            verify_and_map: false,
+            timeout: DEFAULT_TLS_TIMEOUT,
+        }
+    }
+}
+
+impl TlsConfig {
+    // verify_and_map需要先验证客户端证书
+    pub fn verify_client(&self) -> bool {
+        self.verify || self.verify_and_map
+    }
+
+    /**
+     * 加载证书和私钥,创建TLS握手使用的acceptor
+     */
+    pub fn acceptor(&self) -> io::Result<TlsAcceptor> {
+        let provider = Arc::new(ring::default_provider());
+        let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(invalid_data)?;
+        let builder = if self.verify_client() {
+            let ca_file = self.ca_file.as_ref().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "tls verify requires ca_file")
+            })?;
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(ca_file)? {
+                roots.add(cert).map_err(invalid_data)?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .map_err(invalid_data)?;
+            builder.with_client_cert_verifier(verifier)
+        } else {
+            builder.with_no_client_auth()
+        };
+        let config = builder
+            .with_single_cert(load_certs(&self.cert_file)?, load_key(&self.key_file)?)
+            .map_err(invalid_data)?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+fn invalid_data<E: std::error::Error + Send + Sync + 'static>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+fn open(path: &Path) -> io::Result<BufReader<File>> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| io::Error::new(e.kind(), format!("open {} error:{}", path.display(), e)))
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = rustls_pemfile::certs(&mut open(path)?).collect::<io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        let message = format!("no certificate found in {}", path.display());
+        return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    rustls_pemfile::private_key(&mut open(path)?)?.ok_or_else(|| {
+        let message = format!("no private key found in {}", path.display());
+        io::Error::new(io::ErrorKind::InvalidData, message)
+    })
+}
+
+/**
+ * 客户端证书中可以映射成用户名的身份,没有证书时为空
+ */
+pub fn peer_identities(conn: &TlsStream<TcpStream>) -> Vec<String> {
+    let cert = match conn.get_ref().1.peer_certificates() {
+        Some([cert, ..]) => cert,
+        _ => return Vec::new(),
+    };
+    let cert = match X509Certificate::from_der(cert) {
+        Ok((_, cert)) => cert,
+        Err(_) => return Vec::new(),
+    };
+    let mut identities = Vec::new();
+    if let Ok(Some(san)) = cert.subject_alternative_name() {
+        for name in san.value.general_names.iter() {
+            match name {
+                GeneralName::RFC822Name(email) => identities.push(email.to_string()),
+                GeneralName::DNSName(dns) => identities.push(dns.to_string()),
+                _ => {}
+            }
+        }
+    }
+    identities.push(cert.subject().to_string());
+    identities
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_acceptor_errors() {
+        let config = TlsConfig {
+            cert_file: PathBuf::from("/nonexistent/server.pem"),
+            key_file: PathBuf::from("/nonexistent/server-key.pem"),
+            ..Default::default()
+        };
+        let err = config.acceptor().err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err.to_string().contains("/nonexistent/server.pem"));
+
+        let config = TlsConfig {
+            verify_and_map: true,
+            ..config
+        };
+        assert!(config.verify_client());
+        let err = config.acceptor().err().unwrap();
+        assert_eq!(err.to_string(), "tls verify requires ca_file");
+    }
+}
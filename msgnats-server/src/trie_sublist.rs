@@ -0,0 +1,405 @@
+use crate::errors::{NError, Result, ERROR_INVALID_SUBJECT, ERROR_SUBSCRIBTION_NOT_FOUND};
+use crate::simple_sublist::{
+    ArcSubResult, ArcSubscription, ArcSubscriptionWrapper, SubListTrait, SubResult,
+};
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::Arc,
+};
+
+/**
+基于Trie树的订阅关系查找,按照NATS的语义支持模糊匹配:
+subject以.分隔成token,
+`*` 匹配任意一个token, 例如 orders.*.created 可以匹配 orders.1.created
+`>` 匹配剩余的一个或多个token,只能出现在最后, 例如 metrics.> 可以匹配 metrics.cpu.load
+*/
+// 单个token的通配符
+pub const PWC: &str = "*";
+// 剩余所有token的通配符
+pub const FWC: &str = ">";
+
+// Trie树的一层,保存下一个token对应的节点以及两种通配符节点
+#[derive(Debug, Default)]
+struct Level {
+    nodes: HashMap<String, Node>,
+    pwc: Option<Box<Node>>,
+    fwc: Option<Box<Node>>,
+}
+
+impl Level {
+    fn is_empty(&self) -> bool {
+        self.nodes.is_empty() && self.pwc.is_none() && self.fwc.is_none()
+    }
+}
+
+// Trie树节点,订阅主题在这个节点结束时订阅保存在这里
+#[derive(Debug, Default)]
+struct Node {
+    next: Level,
+    subs: BTreeSet<ArcSubscriptionWrapper>,
+    qsubs: HashMap<String, BTreeSet<ArcSubscriptionWrapper>>,
+}
+
+impl Node {
+    fn is_empty(&self) -> bool {
+        self.next.is_empty() && self.subs.is_empty() && self.qsubs.is_empty()
+    }
+
+    // 返回是否真正加入,同一个订阅重复插入时返回false
+    fn add_sub(&mut self, sub: ArcSubscription) -> bool {
+        if let Some(ref q) = sub.queue {
+            let subs = self.qsubs.entry(q.clone()).or_default();
+            subs.insert(ArcSubscriptionWrapper(sub))
+        } else {
+            self.subs.insert(ArcSubscriptionWrapper(sub))
+        }
+    }
+
+    fn remove_sub(&mut self, sub: &ArcSubscription) -> bool {
+        let wrapper = ArcSubscriptionWrapper(sub.clone());
+        if let Some(ref q) = sub.queue {
+            if let Some(subs) = self.qsubs.get_mut(q) {
+                let removed = subs.remove(&wrapper);
+                if subs.is_empty() {
+                    self.qsubs.remove(q);
+                }
+                return removed;
+            }
+            false
+        } else {
+            self.subs.remove(&wrapper)
+        }
+    }
+}
+
+/**
+ * 订阅主题校验:token不能为空,`>`只能作为最后一个token
+ * 通配符必须是一个完整的token,a.b*这种形式当作普通字符处理
+ */
+pub fn validate_subject(subject: &str) -> Result<()> {
+    let mut tokens = subject.split('.').peekable();
+    while let Some(token) = tokens.next() {
+        if token.is_empty() || (token == FWC && tokens.peek().is_some()) {
+            return Err(NError::new(ERROR_INVALID_SUBJECT));
+        }
+    }
+    Ok(())
+}
+
+/**
+ * 发布主题校验:除了订阅主题的规则,还不能包含通配符
+ */
+pub fn validate_literal_subject(subject: &str) -> Result<()> {
+    validate_subject(subject)?;
+    if subject.split('.').any(|t| t == PWC || t == FWC) {
+        return Err(NError::new(ERROR_INVALID_SUBJECT));
+    }
+    Ok(())
+}
+
+/**
+ * 判断一个订阅主题(可以包含通配符)是否匹配某个发布主题
+ * 两者都应该是已经校验过的主题
+ * subject也包含通配符时,判断subject能匹配的主题是否都能被pattern匹配,比如a.>包含a.*
+ */
+pub fn subject_matches(pattern: &str, subject: &str) -> bool {
+    let mut subject_tokens = subject.split('.');
+    for token in pattern.split('.') {
+        match (token, subject_tokens.next()) {
+            (FWC, Some(_)) => return true,
+            (_, Some(FWC)) => return false,
+            (PWC, Some(_)) => {}
+            (t, Some(s)) if t == s => {}
+            _ => return false,
+        }
+    }
+    subject_tokens.next().is_none()
+}
+
+/**
+ * 判断两个订阅主题(都可以包含通配符)是否存在同时匹配的发布主题,比如a.*和*.b
+ */
+pub fn subjects_overlap(a: &str, b: &str) -> bool {
+    let mut a_tokens = a.split('.');
+    let mut b_tokens = b.split('.');
+    loop {
+        match (a_tokens.next(), b_tokens.next()) {
+            (Some(FWC), Some(_)) | (Some(_), Some(FWC)) => return true,
+            (Some(PWC), Some(_)) | (Some(_), Some(PWC)) => {}
+            (Some(x), Some(y)) if x == y => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct TrieSubList {
+    root: Level,
+    count: usize,
+}
+
+impl TrieSubList {
+    // 递归删除,返回是否删除成功;回溯时清理空节点
+    fn remove_from_level(level: &mut Level, tokens: &[&str], sub: &ArcSubscription) -> bool {
+        let (token, rest) = match tokens.split_first() {
+            Some(t) => t,
+            None => return false,
+        };
+        let slot = match *token {
+            PWC => level.pwc.as_deref_mut(),
+            FWC => level.fwc.as_deref_mut(),
+            _ => level.nodes.get_mut(*token),
+        };
+        let node = match slot {
+            Some(node) => node,
+            None => return false,
+        };
+        let removed = if rest.is_empty() {
+            node.remove_sub(sub)
+        } else {
+            Self::remove_from_level(&mut node.next, rest, sub)
+        };
+        if removed && node.is_empty() {
+            match *token {
+                PWC => level.pwc = None,
+                FWC => level.fwc = None,
+                _ => {
+                    level.nodes.remove(*token);
+                }
+            }
+        }
+        removed
+    }
+
+    // 收集匹配的订阅,queue按照名字合并,同一个group不同模式匹配到的订阅视为同一组
+    fn match_level(
+        level: &Level,
+        tokens: &[&str],
+        r: &mut SubResult,
+        qsubs: &mut HashMap<String, Vec<ArcSubscription>>,
+    ) {
+        let (token, rest) = match tokens.split_first() {
+            Some(t) => t,
+            None => return,
+        };
+        if let Some(ref fwc) = level.fwc {
+            Self::collect(fwc, r, qsubs);
+        }
+        if let Some(ref pwc) = level.pwc {
+            Self::match_node(pwc, rest, r, qsubs);
+        }
+        if let Some(node) = level.nodes.get(*token) {
+            Self::match_node(node, rest, r, qsubs);
+        }
+    }
+
+    fn match_node(
+        node: &Node,
+        rest: &[&str],
+        r: &mut SubResult,
+        qsubs: &mut HashMap<String, Vec<ArcSubscription>>,
+    ) {
+        if rest.is_empty() {
+            Self::collect(node, r, qsubs);
+        } else {
+            Self::match_level(&node.next, rest, r, qsubs);
+        }
+    }
+
+    fn collect(node: &Node, r: &mut SubResult, qsubs: &mut HashMap<String, Vec<ArcSubscription>>) {
+        r.ppubs.extend(node.subs.iter().map(|s| s.0.clone()));
+        for (q, subs) in node.qsubs.iter() {
+            qsubs
+                .entry(q.clone())
+                .or_default()
+                .extend(subs.iter().map(|s| s.0.clone()));
+        }
+    }
+}
+
+impl SubListTrait for TrieSubList {
+    fn insert(&mut self, sub: ArcSubscription) -> Result<()> {
+        validate_subject(&sub.subject)?;
+        let subject = sub.subject.clone();
+        let mut level = &mut self.root;
+        let mut tokens = subject.split('.').peekable();
+        while let Some(token) = tokens.next() {
+            let node = match token {
+                PWC => level.pwc.get_or_insert_with(Default::default).as_mut(),
+                FWC => level.fwc.get_or_insert_with(Default::default).as_mut(),
+                _ => level.nodes.entry(token.to_string()).or_default(),
+            };
+            if tokens.peek().is_none() {
+                if node.add_sub(sub) {
+                    self.count += 1;
+                }
+                return Ok(());
+            }
+            level = &mut node.next;
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, sub: ArcSubscription) -> Result<()> {
+        let tokens: Vec<&str> = sub.subject.split('.').collect();
+        if Self::remove_from_level(&mut self.root, &tokens, &sub) {
+            self.count -= 1;
+            Ok(())
+        } else {
+            Err(NError::new(ERROR_SUBSCRIBTION_NOT_FOUND))
+        }
+    }
+
+    fn match_subject(&self, subject: &str) -> Result<ArcSubResult> {
+        validate_literal_subject(subject)?;
+        let tokens: Vec<&str> = subject.split('.').collect();
+        let mut r = SubResult::new();
+        let mut qsubs = HashMap::new();
+        Self::match_level(&self.root, &tokens, &mut r, &mut qsubs);
+        r.qpubs = qsubs.into_values().collect();
+        Ok(Arc::new(r))
+    }
+
+    fn count(&self) -> usize {
+        self.count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ClientMessageSender;
+    use crate::simple_sublist::SubScription;
+    use tokio::sync::Mutex;
+
+    fn new_sub(subject: &str, queue: Option<&str>, sid: &str) -> ArcSubscription {
+        let sender = Arc::new(Mutex::new(ClientMessageSender::new(tokio::io::sink())));
+        Arc::new(SubScription::new(sender, subject, queue, sid))
+    }
+
+    fn sids(r: &SubResult) -> Vec<String> {
+        let mut v: Vec<String> = r.ppubs.iter().map(|s| s.sid.clone()).collect();
+        v.sort();
+        v
+    }
+
+    #[test]
+    fn test_literal() {
+        let mut sl = TrieSubList::default();
+        let s1 = new_sub("a.b.c", None, "1");
+        sl.insert(s1.clone()).unwrap();
+        sl.insert(new_sub("a.b", None, "2")).unwrap();
+        assert_eq!(sids(&sl.match_subject("a.b.c").unwrap()), vec!["1"]);
+        assert_eq!(sids(&sl.match_subject("a.b").unwrap()), vec!["2"]);
+        assert!(sl.match_subject("a.b.c.d").unwrap().is_empty());
+        sl.remove(s1.clone()).unwrap();
+        assert!(sl.match_subject("a.b.c").unwrap().is_empty());
+        assert!(sl.remove(s1).is_err());
+        assert_eq!(sl.count(), 1);
+    }
+
+    #[test]
+    fn test_duplicate_insert() {
+        let mut sl = TrieSubList::default();
+        let s1 = new_sub("a.b", None, "1");
+        let q1 = new_sub("a.*", Some("q"), "2");
+        for _ in 0..2 {
+            sl.insert(s1.clone()).unwrap();
+            sl.insert(q1.clone()).unwrap();
+        }
+        assert_eq!(sl.count(), 2);
+        sl.remove(s1).unwrap();
+        sl.remove(q1).unwrap();
+        assert_eq!(sl.count(), 0);
+    }
+
+    #[test]
+    fn test_wildcards() {
+        let mut sl = TrieSubList::default();
+        sl.insert(new_sub("orders.*.created", None, "1")).unwrap();
+        sl.insert(new_sub("metrics.>", None, "2")).unwrap();
+        sl.insert(new_sub(">", None, "3")).unwrap();
+        sl.insert(new_sub("*.*.created", None, "4")).unwrap();
+        assert_eq!(
+            sids(&sl.match_subject("orders.42.created").unwrap()),
+            vec!["1", "3", "4"]
+        );
+        assert_eq!(
+            sids(&sl.match_subject("metrics.cpu").unwrap()),
+            vec!["2", "3"]
+        );
+        assert_eq!(
+            sids(&sl.match_subject("metrics.cpu.load").unwrap()),
+            vec!["2", "3"]
+        );
+        // > 至少匹配一个token
+        assert_eq!(sids(&sl.match_subject("metrics").unwrap()), vec!["3"]);
+        assert_eq!(
+            sids(&sl.match_subject("orders.created").unwrap()),
+            vec!["3"]
+        );
+    }
+
+    #[test]
+    fn test_queue_merge() {
+        let mut sl = TrieSubList::default();
+        sl.insert(new_sub("jobs.*", Some("workers"), "1")).unwrap();
+        sl.insert(new_sub("jobs.>", Some("workers"), "2")).unwrap();
+        sl.insert(new_sub("jobs.run", Some("other"), "3")).unwrap();
+        let r = sl.match_subject("jobs.run").unwrap();
+        assert!(r.ppubs.is_empty());
+        let mut sizes: Vec<usize> = r.qpubs.iter().map(|q| q.len()).collect();
+        sizes.sort();
+        assert_eq!(sizes, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_subject_matches() {
+        assert!(subject_matches("a.b", "a.b"));
+        assert!(subject_matches("a.*", "a.b"));
+        assert!(subject_matches("a.>", "a.b.c"));
+        assert!(subject_matches(">", "a"));
+        assert!(!subject_matches("a.>", "a"));
+        assert!(!subject_matches("a.*", "a.b.c"));
+        assert!(!subject_matches("a.b.c", "a.b"));
+        assert!(subject_matches("a.>", "a.*.c"));
+        assert!(subject_matches("a.*", "a.*"));
+        assert!(!subject_matches("a.*", "a.>"));
+        assert!(!subject_matches("a.b", "a.*"));
+    }
+
+    #[test]
+    fn test_subjects_overlap() {
+        assert!(subjects_overlap("a.b", "a.b"));
+        assert!(subjects_overlap("a.*", "*.b"));
+        assert!(subjects_overlap("a.>", "a.b.c"));
+        assert!(subjects_overlap(">", "a"));
+        assert!(!subjects_overlap("a.>", "a"));
+        assert!(!subjects_overlap("a.*", "a.b.c"));
+        assert!(!subjects_overlap("a.b", "a.c"));
+        assert!(!subjects_overlap("a.*.c", "a.b"));
+    }
+
+    #[test]
+    fn test_invalid_subject() {
+        let mut sl = TrieSubList::default();
+        assert!(sl.insert(new_sub("a..b", None, "1")).is_err());
+        assert!(sl.insert(new_sub("a.>.b", None, "1")).is_err());
+        assert!(sl.insert(new_sub(".a", None, "1")).is_err());
+        assert!(sl.match_subject("a.*").is_err());
+        assert_eq!(sl.count(), 0);
+    }
+
+    #[test]
+    fn test_remove_prunes_nodes() {
+        let mut sl = TrieSubList::default();
+        let s1 = new_sub("a.*.c", None, "1");
+        let s2 = new_sub("a.>", Some("q"), "2");
+        sl.insert(s1.clone()).unwrap();
+        sl.insert(s2.clone()).unwrap();
+        sl.remove(s1).unwrap();
+        sl.remove(s2).unwrap();
+        assert!(sl.root.is_empty());
+    }
+}